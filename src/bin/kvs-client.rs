@@ -0,0 +1,221 @@
+/// This binary is the network client of the database
+/// it sends every command to a running kvs-server instead of opening the files directly
+extern crate clap;
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::kvmessage::{KvMessage, KvResponse};
+use kvs::kvsclient::Kvclient;
+use std::net::SocketAddr;
+use std::process;
+use tracing::debug;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> kvs::Result<()> {
+    setup()?;
+
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .takes_value(true)
+        .default_value(DEFAULT_ADDR);
+
+    let m = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set value")
+                .help("kvs-client set <key> <value> -- Set the value of the key")
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get value")
+                .help("kvs-client get <key> -- Get the value of the key in parameter")
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove value")
+                .help("kvs-client rm <key> -- Delete the key/value ")
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("mset")
+                .about("Set several values")
+                .help("kvs-client mset <key> <value> [<key> <value>...] -- Set the keys at once")
+                .arg(
+                    Arg::with_name("pairs")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("mget")
+                .about("Get several values")
+                .help("kvs-client mget <key>... -- Get the values of the keys, one per line")
+                .arg(
+                    Arg::with_name("keys")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("mdel")
+                .about("Remove several values")
+                .help("kvs-client mdel <key>... -- Delete the keys, none if one is missing")
+                .arg(
+                    Arg::with_name("keys")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("List keys")
+                .help("kvs-client keys [pattern] -- List the keys matching the glob pattern")
+                .arg(Arg::with_name("pattern").index(1))
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Count keys")
+                .help("kvs-client count -- Print the number of keys")
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("exists")
+                .about("Check a key")
+                .help("kvs-client exists <key> -- Print true if the key is in the store")
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(addr_arg),
+        )
+        .get_matches();
+
+    let (message, subcommand) = match m.subcommand() {
+        ("set", Some(sub)) => (
+            KvMessage::Set(
+                sub.value_of("key").unwrap().to_string(),
+                sub.value_of("value").unwrap().to_string(),
+            ),
+            sub,
+        ),
+        ("get", Some(sub)) => (
+            KvMessage::Get(sub.value_of("key").unwrap().to_string()),
+            sub,
+        ),
+        ("rm", Some(sub)) => (
+            KvMessage::Remove(sub.value_of("key").unwrap().to_string()),
+            sub,
+        ),
+        ("mset", Some(sub)) => {
+            let values: Vec<String> = sub.values_of("pairs").unwrap().map(String::from).collect();
+            if !values.len().is_multiple_of(2) {
+                eprintln!("Every key needs a value");
+                process::exit(1);
+            }
+            let pairs = values
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            (KvMessage::MSet(pairs), sub)
+        }
+        ("mget", Some(sub)) => (
+            KvMessage::MGet(sub.values_of("keys").unwrap().map(String::from).collect()),
+            sub,
+        ),
+        ("mdel", Some(sub)) => (
+            KvMessage::MDel(sub.values_of("keys").unwrap().map(String::from).collect()),
+            sub,
+        ),
+        ("keys", Some(sub)) => (
+            KvMessage::Keys(sub.value_of("pattern").map(String::from)),
+            sub,
+        ),
+        ("count", Some(sub)) => (KvMessage::Count, sub),
+        ("exists", Some(sub)) => (
+            KvMessage::Exists(sub.value_of("key").unwrap().to_string()),
+            sub,
+        ),
+        _ => unreachable!(),
+    };
+
+    let addr = match subcommand.value_of("addr").unwrap().parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(x) => {
+            eprintln!("Invalid address: {}", x);
+            process::exit(1);
+        }
+    };
+
+    let client = Kvclient::new(addr);
+    match client.send(&message) {
+        Ok(KvResponse::Ok) => (),
+        Ok(KvResponse::Value(Some(value))) => println!("{}", value),
+        Ok(KvResponse::Value(None)) => println!("Key not found"),
+        Ok(KvResponse::Values(values)) => {
+            for value in values {
+                match value {
+                    Some(value) => println!("{}", value),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        Ok(KvResponse::Keys(keys)) => {
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        Ok(KvResponse::Count(count)) => println!("{}", count),
+        Ok(KvResponse::Exists(exists)) => println!("{}", exists),
+        Ok(KvResponse::NotFound) => {
+            eprintln!("Key not found");
+            process::exit(1);
+        }
+        Ok(KvResponse::Error { code, message }) => {
+            eprintln!("Error ({:?}): {}", code, message);
+            process::exit(1);
+        }
+        Ok(other) => {
+            debug!("Unexpected message from the server: {:?}", other);
+            eprintln!("Unexpected response from the server");
+            process::exit(1);
+        }
+        Err(x) => {
+            debug!("Error when talking to the server: {:?}", x);
+            eprintln!("Could not reach the server at {}", addr);
+            process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn setup() -> kvs::Result<()> {
+    if std::env::var("RUST_LIB_BACKTRACE").is_err() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "1")
+    }
+    color_eyre::install()?;
+
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "warn")
+    }
+    // Logs go to stderr so that stdout only holds the server reply
+    tracing_subscriber::fmt::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    Ok(())
+}
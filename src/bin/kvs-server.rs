@@ -0,0 +1,170 @@
+/// This binary is the network server of the database
+/// it opens the store once and answers the requests sent by kvs-client
+extern crate clap;
+use clap::{App, Arg};
+#[cfg(feature = "sled")]
+use kvs::kvsengine::SledKvsEngine;
+use kvs::kvsengine::{check_engine, KvStoreOptions, KvsScan, MemoryEngine};
+use kvs::kvsserver::Kvserver;
+use kvs::threadpool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
+const DEFAULT_POOL: &str = "shared-queue";
+#[cfg(feature = "sled")]
+const ENGINES: &[&str] = &["kvs", "sled", "memory"];
+#[cfg(not(feature = "sled"))]
+const ENGINES: &[&str] = &["kvs", "memory"];
+
+fn main() -> kvs::Result<()> {
+    setup()?;
+
+    let m = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .takes_value(true)
+                .default_value(DEFAULT_ADDR),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIRECTORY")
+                .takes_value(true)
+                .help("Directory holding the store files - Current directory by default"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .takes_value(true)
+                .possible_values(ENGINES)
+                .default_value(DEFAULT_ENGINE),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("TOML file holding the store options - Only used by the kvs engine"),
+        )
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .value_name("POOL-NAME")
+                .takes_value(true)
+                .possible_values(&["naive", "shared-queue", "rayon"])
+                .default_value(DEFAULT_POOL),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .takes_value(true)
+                .help("Threads running the requests - One per CPU by default"),
+        )
+        .get_matches();
+
+    let addr = match m.value_of("addr").unwrap().parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(x) => {
+            eprintln!("Invalid address: {}", x);
+            process::exit(1);
+        }
+    };
+    let data_dir = match m.value_of("data-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::current_dir()?,
+    };
+    let engine = m.value_of("engine").unwrap();
+    let threads = match m.value_of("threads") {
+        Some(threads) => match threads.parse::<u32>() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                eprintln!("Invalid number of threads: {}", threads);
+                process::exit(1);
+            }
+        },
+        None => std::thread::available_parallelism()
+            .map(|threads| threads.get() as u32)
+            .unwrap_or(4),
+    };
+    let pool = m.value_of("pool").unwrap();
+    let store_options = match m.value_of("config") {
+        Some(config) => match KvStoreOptions::from_file(config) {
+            Ok(options) => options,
+            Err(x) => {
+                error!("Could not read {}: {:?}", config, x);
+                eprintln!("Invalid configuration file {}", config);
+                process::exit(1);
+            }
+        },
+        None => KvStoreOptions::default(),
+    };
+
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Listening address: {}", addr);
+    info!("Data directory: {}", data_dir.display());
+    info!("Storage engine: {}", engine);
+    info!("Store options: {:?}", store_options);
+    info!("Thread pool: {} with {} threads", pool, threads);
+
+    // The memory engine writes nothing : The data directory is left alone
+    if engine != "memory" {
+        fs::create_dir_all(&data_dir)?;
+        if let Err(x) = check_engine(&data_dir, engine) {
+            error!("Refusing to start: {:?}", x);
+            eprintln!("Data directory was not created by the {} engine", engine);
+            process::exit(1);
+        }
+    }
+
+    match engine {
+        "memory" => serve(Kvserver::new(addr, MemoryEngine::new()), pool, threads),
+        #[cfg(feature = "sled")]
+        "sled" => serve(
+            Kvserver::new(addr, SledKvsEngine::open(data_dir)?),
+            pool,
+            threads,
+        ),
+        _ => serve(
+            Kvserver::new(addr, store_options.open(data_dir)?),
+            pool,
+            threads,
+        ),
+    }
+}
+
+fn serve<E: KvsScan>(mut server: Kvserver<E>, pool: &str, threads: u32) -> kvs::Result<()> {
+    match pool {
+        "naive" => server.run_with_pool(NaiveThreadPool::new(threads)?),
+        "rayon" => server.run_with_pool(RayonThreadPool::new(threads)?),
+        _ => server.run_with_pool(SharedQueueThreadPool::new(threads)?),
+    }
+}
+
+fn setup() -> kvs::Result<()> {
+    if std::env::var("RUST_LIB_BACKTRACE").is_err() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "1")
+    }
+    color_eyre::install()?;
+
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info")
+    }
+    // Logs go to stderr as the server has nothing to print on stdout
+    tracing_subscriber::fmt::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    Ok(())
+}
@@ -2,9 +2,10 @@
 /// it is used mainly for debug purpose as it go through the KvStore structure directly
 /// We can trace direct problems without network layer
 extern crate clap;
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, SubCommand};
 use kvs::kvsengine::kvstore::KvStore;
 use kvs::kvsengine::*;
+use kvs::KvsError;
 use std::env;
 use std::process;
 use tracing::debug;
@@ -14,27 +15,57 @@ fn main() -> kvs::Result<()> {
     setup()?;
 
     let m = App::new(env!("CARGO_PKG_NAME"))
+        .setting(AppSettings::ArgRequiredElseHelp)
         .subcommand(
             SubCommand::with_name("set")
                 .about("set value")
-                .arg(Arg::with_name("key").takes_value(true).index(1))
-                .arg(Arg::with_name("value").takes_value(true).index(2)),
+                .arg(Arg::with_name("key").required(true).index(1))
+                .arg(Arg::with_name("value").required(true).index(2)),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .about("Get value")
                 .help("kvs get <key> -- Get the value of the key in parameter")
-                .arg(Arg::with_name("key").takes_value(true).index(1)),
+                .arg(Arg::with_name("key").required(true).index(1)),
         )
         .subcommand(
             SubCommand::with_name("rm")
                 .about("Remove value")
                 .help("kvs rm <key> -- Delete the key/value ")
-                .arg(Arg::with_name("key").takes_value(true).index(1)),
+                .arg(Arg::with_name("key").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("List keys")
+                .help("kvs keys [pattern] -- List the keys matching the glob pattern")
+                .arg(Arg::with_name("pattern").index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Count keys")
+                .help("kvs count -- Print the number of keys"),
+        )
+        .subcommand(
+            SubCommand::with_name("exists")
+                .about("Check a key")
+                .help("kvs exists <key> -- Print true if the key is in the store")
+                .arg(Arg::with_name("key").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Migrate data files")
+                .help(
+                    "kvs migrate -- Convert the data files of older versions to the current format",
+                ),
         )
         .arg(Arg::with_name("version").short("V").long("V"))
         .arg(Arg::with_name("open").short("o").long("o"))
-        .arg(Arg::with_name("compaction").short("c").long("c"))
+        .arg(
+            Arg::with_name("compaction")
+                .short("c")
+                .long("compaction")
+                .help("Compact the data files of the current directory"),
+        )
         .get_matches();
 
     if m.is_present("version") {
@@ -43,12 +74,34 @@ fn main() -> kvs::Result<()> {
         process::exit(4);
     }
 
+    if m.subcommand_matches("migrate").is_some() {
+        match KvStore::migrate(std::env::current_dir()?) {
+            Ok(migrated) => println!("{} records migrated", migrated),
+            Err(x) => {
+                debug!("Error during migration: {:?}", x);
+                eprintln!("Could not migrate the data files");
+                process::exit(1);
+            }
+        }
+        process::exit(0);
+    }
+
+    if m.is_present("compaction") {
+        let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+        if let Err(x) = my_store.compaction() {
+            debug!("Error during compaction: {:?}", x);
+            eprintln!("Could not compact the data files");
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
     if m.is_present("set") {
         debug!("Set command has beed issued");
         if let Some(subcommand) = m.subcommand_matches("set") {
             if subcommand.is_present("key") && subcommand.is_present("value") {
                 debug!("Value and Key have been provided.");
-                let mut my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+                let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
                 my_store.set(
                     subcommand.value_of("key").unwrap().to_string(),
                     subcommand.value_of("value").unwrap().to_string(),
@@ -61,23 +114,69 @@ fn main() -> kvs::Result<()> {
 
     if m.is_present("get") {
         if let Some(subcommand) = m.subcommand_matches("get") {
-            let mut my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+            let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
             match my_store.get(subcommand.value_of("key").unwrap().to_string()) {
-                Ok(x) => {
-                    if let Some(z) = x {
-                        println!("{}", z);
-                        process::exit(0);
-                    }
+                Ok(Some(value)) => {
+                    println!("{}", value);
+                    process::exit(0);
+                }
+                Ok(None) => {
+                    println!("Key not found");
+                    process::exit(0);
                 }
                 Err(x) => {
                     debug!("Error when getting the value: {:?}", x);
-                    eprintln!("Key not found");
+                    eprintln!("Could not read the value");
                     process::exit(1);
                 }
             }
         }
     }
 
+    if m.is_present("rm") {
+        if let Some(subcommand) = m.subcommand_matches("rm") {
+            let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+            match my_store.remove(subcommand.value_of("key").unwrap().to_string()) {
+                Ok(()) => {
+                    drop(my_store);
+                    process::exit(0);
+                }
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    process::exit(1);
+                }
+                Err(x) => {
+                    debug!("Error when removing the value: {:?}", x);
+                    eprintln!("Could not remove the value");
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(subcommand) = m.subcommand_matches("keys") {
+        let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+        for key in my_store.keys(subcommand.value_of("pattern"))? {
+            println!("{}", key);
+        }
+        process::exit(0);
+    }
+
+    if m.subcommand_matches("count").is_some() {
+        let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+        println!("{}", my_store.count()?);
+        process::exit(0);
+    }
+
+    if let Some(subcommand) = m.subcommand_matches("exists") {
+        let my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+        println!(
+            "{}",
+            my_store.exists(subcommand.value_of("key").unwrap().to_string())?
+        );
+        process::exit(0);
+    }
+
     Ok(())
 }
 
@@ -90,8 +189,10 @@ fn setup() -> kvs::Result<()> {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug")
     }
+    // Logs go to stderr so that stdout only holds the command output
     tracing_subscriber::fmt::fmt()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .init();
 
     Ok(())
@@ -25,6 +25,71 @@ pub enum KvsError {
 
     /// Errors from the color_eyere library
     EyreError(color_eyre::Report),
+
+    /// wrapper for bincode errors - Used to encode messages sent over the network
+    Bincode(bincode::Error),
+
+    /// The server closed the connexion or did not answer in time
+    NoResponse,
+
+    /// A record does not match its checksum
+    Corrupted {
+        /// Data file holding the record
+        file: std::path::PathBuf,
+        /// Offset of the record in the file
+        offset: u64,
+    },
+
+    /// The data directory was created by another engine
+    WrongEngine {
+        /// Engine we tried to open the directory with
+        expected: String,
+        /// Engine recorded in the directory
+        found: String,
+    },
+
+    /// The store was opened read-only
+    ReadOnly,
+
+    /// Store options that cannot be used
+    InvalidOptions(String),
+
+    /// wrapper for toml errors - Used to read the store options
+    Toml(toml::de::Error),
+
+    /// A thread panicked while holding a lock of the store
+    Poisoned,
+
+    /// The thread pool could not be built
+    ThreadPool(String),
+
+    /// The server refused the connexion - Holds its response to the handshake
+    Rejected(crate::kvmessage::KvResponse),
+
+    /// wrapper for tokio task errors - A blocking job panicked or was cancelled
+    #[cfg(feature = "async")]
+    Join(tokio::task::JoinError),
+
+    /// wrapper for sled errors
+    #[cfg(feature = "sled")]
+    Sled(sled::Error),
+
+    /// A key or a value read from the engine is not valid UTF-8
+    Utf8(std::string::FromUtf8Error),
+
+    /// A key or a value is too long to fit in a record
+    TooLarge,
+
+    /// The file was written by an older version - See KvStore::migrate
+    LegacyFormat(std::path::PathBuf),
+
+    /// The file was written in a format version this version does not know
+    UnsupportedVersion {
+        /// File holding the unknown format
+        file: std::path::PathBuf,
+        /// Version found in its header
+        version: u32,
+    },
 }
 impl From<std::io::Error> for KvsError {
     fn from(err: std::io::Error) -> KvsError {
@@ -44,5 +109,43 @@ impl From<serde_json::error::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
+impl From<toml::de::Error> for KvsError {
+    fn from(err: toml::de::Error) -> KvsError {
+        KvsError::Toml(err)
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for KvsError {
+    fn from(_: std::sync::PoisonError<T>) -> KvsError {
+        KvsError::Poisoned
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<tokio::task::JoinError> for KvsError {
+    fn from(err: tokio::task::JoinError) -> KvsError {
+        KvsError::Join(err)
+    }
+}
+
+#[cfg(feature = "sled")]
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(err: std::string::FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
 /// Result<T>
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -1,14 +1,121 @@
+use crate::errors::KvsError;
 use serde::{Deserialize, Serialize};
 
-/// Enum used to communicate between client and server
+/// Version of the protocol spoken between client and server.
+/// It has to be bumped every time KvMessage or KvResponse changes.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Enum used by the client to send requests to the server
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvMessage {
+    /// First message of every connexion - Holds the protocol version of the client
+    Handshake(u32),
     /// To set value in the data-store
     Set(String, String),
     /// To get value from the data-store
     Get(String),
     ///To remove value from the datastore
     Remove(String),
-    /// Response sent by the server to client
-    Response(String),
+    /// To set several values in one request - Written all at once by the store
+    MSet(Vec<(String, String)>),
+    /// To get several values in one request
+    MGet(Vec<String>),
+    /// To remove several values in one request - Nothing is removed if a key is missing
+    MDel(Vec<String>),
+    /// To list the pairs with a key from start (included) to end (excluded), in key order.
+    /// The answer is a page of at most limit pairs, the next one starts at its next key
+    Scan {
+        /// First key of the page - From the first key of the store if None
+        start: Option<String>,
+        /// Key ending the scan - Up to the last key of the store if None
+        end: Option<String>,
+        /// Maximum number of pairs in the page - The server may send less
+        limit: u32,
+    },
+    /// To list the keys matching a glob pattern - Every key if None
+    Keys(Option<String>),
+    /// To count the keys of the datastore
+    Count,
+    /// To know if a key is in the datastore
+    Exists(String),
+    /// To set a value that expires after the given number of milliseconds
+    SetWithTtl(String, String, u64),
+    /// To make an existing key expire after the given number of milliseconds
+    Expire(String, u64),
+    /// To get the milliseconds left before a key expires
+    Ttl(String),
+    /// To set a value in the data-store - Keys and values are any bytes
+    SetBytes(Vec<u8>, Vec<u8>),
+    /// To get a value from the data-store as bytes
+    GetBytes(Vec<u8>),
+    /// To remove a value from the data-store by its bytes
+    RemoveBytes(Vec<u8>),
+}
+
+/// Enum used by the server to answer a request
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum KvResponse {
+    /// Answer to the handshake - Holds the protocol version of the server
+    Handshake(u32),
+    /// The request was processed
+    Ok,
+    /// Answer to a get - None if the key is not in the store
+    Value(Option<String>),
+    /// Answer to a multiple get - One value per key, in the order of the request
+    Values(Vec<Option<String>>),
+    /// Answer to a scan
+    Page {
+        /// Pairs of the page, in key order
+        pairs: Vec<(String, String)>,
+        /// Start of the next page - None once the scan is over
+        next: Option<String>,
+    },
+    /// Answer to keys - In key order
+    Keys(Vec<String>),
+    /// Answer to count
+    Count(u64),
+    /// Answer to exists
+    Exists(bool),
+    /// Answer to ttl - Milliseconds left, None if the key never expires
+    Ttl(Option<u64>),
+    /// Answer to a get of bytes - None if the key is not in the store
+    Bytes(Option<Vec<u8>>),
+    /// The key targeted by the request does not exist
+    NotFound,
+    /// The request could not be processed
+    Error {
+        /// Kind of error, clients should branch on it rather than on the message
+        code: ErrorCode,
+        /// Human readable description of the error
+        message: String,
+    },
+}
+
+/// Error codes sent in KvResponse::Error
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// Client and server do not speak the same protocol version
+    VersionMismatch,
+    /// A request was sent before the handshake
+    HandshakeRequired,
+    /// The request could not be decoded
+    InvalidRequest,
+    /// The storage engine failed to process the request
+    Storage,
+}
+
+impl KvResponse {
+    /// Build an error response
+    pub fn error(code: ErrorCode, message: String) -> KvResponse {
+        KvResponse::Error { code, message }
+    }
+}
+
+impl From<KvsError> for KvResponse {
+    fn from(err: KvsError) -> KvResponse {
+        match err {
+            KvsError::KeyNotFound => KvResponse::NotFound,
+            err => KvResponse::error(ErrorCode::Storage, format!("{:?}", err)),
+        }
+    }
 }
@@ -0,0 +1,11 @@
+//! Async server and client, built on tokio - Only compiled with the async feature.
+//! Messages are the bincode encoded KvMessage and KvResponse of the sync path, each one sent
+//! in a frame prefixed by its length. The handshake is the same as well.
+//! The framing differs from the one of message-io : Async clients talk to the async server only
+mod client;
+mod engine;
+mod server;
+
+pub use self::client::KvsClient;
+pub use self::engine::AsyncEngine;
+pub use self::server::KvsAsyncServer;
@@ -0,0 +1,55 @@
+use crate::errors::*;
+use crate::kvmessage::{KvMessage, KvResponse, PROTOCOL_VERSION};
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::debug;
+
+// How long we wait for the server to answer before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Async client - Unlike Kvclient the connexion is kept open between requests
+pub struct KvsClient {
+    framed: Framed<TcpStream, LengthDelimitedCodec>,
+}
+
+impl KvsClient {
+    /// Connect to the server and go through the handshake.
+    /// If the server does not speak our protocol version its error response is returned
+    /// as KvsError::Rejected
+    pub async fn connect(remote_addr: SocketAddr) -> Result<KvsClient> {
+        let socket = TcpStream::connect(remote_addr).await?;
+        let mut client = KvsClient {
+            framed: Framed::new(socket, LengthDelimitedCodec::new()),
+        };
+        match client.send(&KvMessage::Handshake(PROTOCOL_VERSION)).await? {
+            KvResponse::Handshake(version) => {
+                debug!("Server speaks protocol version {}", version);
+                Ok(client)
+            }
+            response => Err(KvsError::Rejected(response)),
+        }
+    }
+
+    /// Send one message to the server and wait for its response
+    pub async fn send(&mut self, message: &KvMessage) -> Result<KvResponse> {
+        self.framed
+            .send(Bytes::from(bincode::serialize(message)?))
+            .await?;
+        match tokio::time::timeout(RESPONSE_TIMEOUT, self.framed.next()).await {
+            Ok(Some(frame)) => Ok(bincode::deserialize::<KvResponse>(&frame?)?),
+            Ok(None) => {
+                debug!("Server closed the connexion before answering");
+                Err(KvsError::NoResponse)
+            }
+            Err(_) => {
+                debug!("No response from the server after {:?}", RESPONSE_TIMEOUT);
+                Err(KvsError::NoResponse)
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::errors::*;
+use crate::kvsengine::KvsEngine;
+use std::sync::{Arc, Mutex};
+
+/// Async front of a KvsEngine : Every call runs on the blocking pool of tokio
+/// so that disk I/O never stalls the tasks of the runtime
+#[derive(Clone)]
+pub struct AsyncEngine<E: KvsEngine> {
+    // Engines are not Sync because of their readers : Handles are cloned out of the lock
+    engine: Arc<Mutex<E>>,
+    // Handles of the engine between two calls - Each one keeps its readers open
+    idle_engines: Arc<Mutex<Vec<E>>>,
+}
+
+impl<E: KvsEngine> AsyncEngine<E> {
+    /// Wrap an engine
+    pub fn new(engine: E) -> AsyncEngine<E> {
+        AsyncEngine {
+            engine: Arc::new(Mutex::new(engine)),
+            idle_engines: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Set the value of a key
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    /// Get the value of a key - None if the key is not in the store
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    /// Remove a key
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |engine| engine.remove(key)).await
+    }
+
+    /// Set the value of a key - Keys and values are any bytes
+    pub async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.run(move |engine| engine.set_bytes(key, value)).await
+    }
+
+    /// Get the value of a key as bytes - None if the key is not in the store
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |engine| engine.get_bytes(key)).await
+    }
+
+    /// Remove a key given as bytes
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.run(move |engine| engine.remove_bytes(key)).await
+    }
+
+    /// Run a closure against a handle of the engine on the blocking pool
+    pub(crate) async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let idle_engine = self.idle_engines.lock()?.pop();
+        let engine = match idle_engine {
+            Some(engine) => engine,
+            None => self.engine.lock()?.clone(),
+        };
+        let idle_engines = Arc::clone(&self.idle_engines);
+        tokio::task::spawn_blocking(move || {
+            let result = job(&engine);
+            if let Ok(mut idle_engines) = idle_engines.lock() {
+                idle_engines.push(engine);
+            }
+            result
+        })
+        .await?
+    }
+}
@@ -0,0 +1,88 @@
+use super::AsyncEngine;
+use crate::errors::*;
+use crate::kvmessage::{ErrorCode, KvMessage, KvResponse, PROTOCOL_VERSION};
+use crate::kvsengine::KvsScan;
+use crate::kvsserver::process_request;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{debug, info};
+
+/// Async server structure - Same settings as Kvserver
+pub struct KvsAsyncServer<E: KvsScan> {
+    local_socketadr: SocketAddr,
+    engine: AsyncEngine<E>,
+}
+
+impl<E: KvsScan> KvsAsyncServer<E> {
+    /// Initializer of the server struct
+    /// The engine is opened by the caller, its calls are run on the blocking pool of tokio
+    pub fn new(local_addr: SocketAddr, engine: E) -> KvsAsyncServer<E> {
+        KvsAsyncServer {
+            local_socketadr: local_addr,
+            engine: AsyncEngine::new(engine),
+        }
+    }
+
+    /// Serve every connexion in its own task.
+    /// Must be awaited from a tokio runtime
+    pub async fn run_server(&self) -> Result<()> {
+        let engine = self.engine.clone();
+        let listener = TcpListener::bind(self.local_socketadr).await?;
+        info!("Listening to connexions on {}...", self.local_socketadr);
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            info!("New connexion from {}", peer);
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                if let Err(x) = serve_connexion(engine, socket).await {
+                    debug!("Connexion with {} failed : {:?}", peer, x);
+                }
+                info!("{} just disconnected", peer);
+            });
+        }
+    }
+}
+
+/// Answer the requests of one client until it disconnects
+async fn serve_connexion<E: KvsScan>(engine: AsyncEngine<E>, socket: TcpStream) -> Result<()> {
+    let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+    let mut greeted = false;
+    while let Some(frame) = framed.next().await {
+        let frame = frame?;
+        let response = match bincode::deserialize::<KvMessage>(&frame) {
+            Ok(KvMessage::Handshake(version)) => {
+                if version == PROTOCOL_VERSION {
+                    greeted = true;
+                    KvResponse::Handshake(PROTOCOL_VERSION)
+                } else {
+                    debug!("Client speaks protocol version {}", version);
+                    KvResponse::error(
+                        ErrorCode::VersionMismatch,
+                        format!("Server speaks protocol version {}", PROTOCOL_VERSION),
+                    )
+                }
+            }
+            Ok(message) if greeted => {
+                engine
+                    .run(move |store| Ok(process_request(store, message)))
+                    .await?
+            }
+            Ok(_) => KvResponse::error(
+                ErrorCode::HandshakeRequired,
+                "Handshake must be sent first".to_string(),
+            ),
+            Err(err) => {
+                debug!("Could not decode the request : {:?}", err);
+                KvResponse::error(ErrorCode::InvalidRequest, format!("{}", err))
+            }
+        };
+        framed
+            .send(Bytes::from(bincode::serialize(&response)?))
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use crate::errors::*;
+use crate::kvmessage::{KvMessage, KvResponse, PROTOCOL_VERSION};
+
+use message_io::network::{NetEvent, Transport};
+use message_io::node::{self, NodeEvent};
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::debug;
+
+// How long we wait for the server to answer before giving up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client structure - Only hold the address of the server we talk to
+pub struct Kvclient {
+    remote_socketadr: SocketAddr,
+}
+
+impl Kvclient {
+    /// Initializer of the client struct
+    pub fn new(remote_addr: SocketAddr) -> Kvclient {
+        Kvclient {
+            remote_socketadr: remote_addr,
+        }
+    }
+
+    /// Send one message to the server and wait for its response.
+    /// A new connexion is opened for every request, which is enough for a command line client.
+    /// The handshake is done first: if the server does not speak our protocol version
+    /// its error response is returned and the request is never sent.
+    pub fn send(&self, message: &KvMessage) -> Result<KvResponse> {
+        let (handler, listener) = node::split::<()>();
+        let (server, _) = handler
+            .network()
+            .connect_sync(Transport::FramedTcp, self.remote_socketadr)?;
+
+        let handshake = bincode::serialize(&KvMessage::Handshake(PROTOCOL_VERSION))?;
+        let request = bincode::serialize(message)?;
+        handler.network().send(server, &handshake);
+        // The signal is only used as a timeout
+        handler.signals().send_with_timer((), RESPONSE_TIMEOUT);
+
+        let mut handshake_done = false;
+        let mut response: Option<Result<KvResponse>> = None;
+        listener.for_each(|event| match event {
+            NodeEvent::Network(NetEvent::Message(_, input_data)) => {
+                // Same safety as on the server side : 0 bytes long frames are ignored
+                if input_data.is_empty() {
+                    return;
+                }
+                match bincode::deserialize::<KvResponse>(input_data) {
+                    Ok(KvResponse::Handshake(version)) if !handshake_done => {
+                        debug!("Server speaks protocol version {}", version);
+                        handshake_done = true;
+                        handler.network().send(server, &request);
+                    }
+                    Ok(reply) => {
+                        response = Some(Ok(reply));
+                        handler.stop();
+                    }
+                    Err(err) => {
+                        response = Some(Err(KvsError::from(err)));
+                        handler.stop();
+                    }
+                }
+            }
+            NodeEvent::Network(NetEvent::Disconnected(_)) => {
+                debug!("Server closed the connexion before answering");
+                handler.stop();
+            }
+            NodeEvent::Network(_) => (),
+            NodeEvent::Signal(_) => {
+                debug!("No response from the server after {:?}", RESPONSE_TIMEOUT);
+                handler.stop();
+            }
+        });
+
+        response.unwrap_or(Err(KvsError::NoResponse))
+    }
+}
@@ -1,16 +1,241 @@
+pub use crate::errors::KvsError;
 pub use crate::Result;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+mod glob;
 /// KvStore
 pub mod kvstore;
-pub use kvstore::KvStore;
+/// MemoryEngine
+pub mod memory;
+pub use glob::glob_match;
+/// SledKvsEngine
+#[cfg(feature = "sled")]
+pub mod sledengine;
+pub use kvstore::{CompactionPolicy, KvScan, KvStore, KvStoreOptions, SyncPolicy, WriteBatch};
+pub use memory::MemoryEngine;
+#[cfg(feature = "sled")]
+pub use sledengine::SledKvsEngine;
 
 /// KvsEngine trait used if we wanted to implemet new storage engine
-pub trait KvsEngine {
+/// An engine is a handle : Its clones share the same data and can be sent to other threads.
+/// Keys and values are bytes, the String methods are a UTF-8 layer on top of them.
+/// Only set, get and remove have a bytes version : Expiry and the multi-key methods take
+/// String keys and values, so keys that are not UTF-8 can neither expire nor go through them
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Set the value of a key
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the value of a key - None if the key is not in the store
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a key
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
     /// set function prototype
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
-    /// get function prototype
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// get function prototype - A value that is not UTF-8 is an error
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// remove function prototype
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set the value of a key for the given time - Afterwards the key is absent
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// Make an existing key expire after the given time
+    fn expire(&self, key: String, ttl: Duration) -> Result<()>;
+
+    /// Time left before the key expires - None if it never does
+    fn ttl(&self, key: String) -> Result<Option<Duration>>;
+
+    /// Set several keys at once.
+    /// The default sets them one by one : A failure leaves the first keys set
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Get several keys at once - Values come in the order of the keys
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Remove several keys at once.
+    /// The default removes them one by one and stops at the first key not found
+    fn remove_many(&self, keys: Vec<String>) -> Result<()> {
+        for key in keys {
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// True if the key is in the store - The default reads its value
+    fn exists(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+/// Extension of KvsEngine for engines keeping their keys in order.
+/// Listings are made of Strings : Pairs whose key or value is not UTF-8 are skipped
+pub trait KvsScan: KvsEngine {
+    /// Pairs whose key is in the range, in key order or in reverse order.
+    /// At most limit pairs are returned, starting from the end of the range when reversed
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>>;
+
+    /// Pairs whose key is in the range, in key order
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.scan_range(
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+            None,
+            false,
+        )
+    }
+
+    /// Pairs whose key is in the range, in reverse key order
+    fn scan_rev<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        self.scan_range(
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+            None,
+            true,
+        )
+    }
+
+    /// Pairs whose key starts with the prefix, in key order
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan_range(prefix_range(prefix), None, false)
+    }
+
+    /// Keys matching the glob pattern, every key if None, in key order.
+    /// The default scans the pairs, engines should answer from their index instead
+    fn keys(&self, pattern: Option<&str>) -> Result<Vec<String>> {
+        let pattern = pattern.unwrap_or("*");
+        Ok(self
+            .scan_range(prefix_range(&glob::literal_prefix(pattern)), None, false)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| glob_match(pattern, key))
+            .collect())
+    }
+
+    /// Number of keys in the store, keys that are not UTF-8 included.
+    /// The default counts the listed keys, engines should count all of them instead
+    fn count(&self) -> Result<u64> {
+        Ok(self.keys(None)?.len() as u64)
+    }
+}
+
+/// Range of the keys starting with the prefix.
+/// Its end is the prefix with its last character bumped, characters that cannot be bumped
+/// are dropped first : The range is unbounded when none is left
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // Skip the surrogates, they are not characters
+        let next = match last {
+            '\u{d7ff}' => Some('\u{e000}'),
+            _ => std::char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return (
+                Bound::Included(prefix.to_string()),
+                Bound::Excluded(end.into_iter().collect()),
+            );
+        }
+    }
+    (Bound::Included(prefix.to_string()), Bound::Unbounded)
+}
+
+/// Range of string keys as a range of bytes - Strings and their bytes sort the same way
+pub(crate) fn bytes_range(
+    range: (Bound<String>, Bound<String>),
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        range.0.map(String::into_bytes),
+        range.1.map(String::into_bytes),
+    )
+}
+
+/// Pair of bytes read by a scan as strings - None if the key or the value is not UTF-8
+pub(crate) fn utf8_pair((key, value): (Vec<u8>, Vec<u8>)) -> Option<(String, String)> {
+    String::from_utf8(key)
+        .ok()
+        .zip(String::from_utf8(value).ok())
+}
+
+/// Milliseconds since the epoch - Used for record timestamps and expiry
+pub(crate) fn now_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Name of the file holding the engine that created a data directory
+const ENGINE_FILE: &str = "engine";
+
+/// Check that a data directory belongs to the given engine.
+/// The name of the engine is written the first time the directory is used,
+/// afterwards any other engine is rejected so that we never read files we do not understand.
+/// A directory without engine file is told by the files found in it : Directories filled by
+/// the kvs command or by older versions have none. The engine file is only written when no
+/// file of any engine is there.
+pub fn check_engine(directory: &Path, engine: &str) -> Result<()> {
+    let engine_file = directory.join(ENGINE_FILE);
+    let found = match fs::read_to_string(&engine_file) {
+        Ok(found) => found.trim().to_string(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => match detect_engine(directory)? {
+            Some(found) => found.to_string(),
+            None => {
+                fs::write(&engine_file, engine)?;
+                return Ok(());
+            }
+        },
+        Err(err) => return Err(KvsError::Io(err)),
+    };
+    if found != engine {
+        return Err(KvsError::WrongEngine {
+            expected: engine.to_string(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+/// Engine whose files are in a data directory, if any
+fn detect_engine(directory: &Path) -> Result<Option<&'static str>> {
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name == "kvmanifest"
+            || name == "kvindex.idx"
+            || name.ends_with(".bdd")
+            || name.ends_with(".hint")
+        {
+            return Ok(Some("kvs"));
+        }
+        if name == "conf" || name == "db" {
+            return Ok(Some("sled"));
+        }
+    }
+    Ok(None)
 }
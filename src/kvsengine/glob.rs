@@ -0,0 +1,105 @@
+//! Glob-style patterns used to list keys :
+//! `*` matches any sequence, `?` any character, `[abc]`, `[a-z]` and `[!a-z]` a character
+//! of the class or out of it, and `\` escapes the next character.
+//! A `[` without its closing `]` is an ordinary character.
+
+/// True if the whole key matches the pattern
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // After a mismatch, the last star swallows one more character and matching goes on from
+    // there : Positions in the pattern after the star and in the key where the star stops
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(next) = match_char(&pattern, p, key[k]) {
+            p = next;
+            k += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Characters of the pattern before its first wildcard : Every matching key starts with them
+pub fn literal_prefix(pattern: &str) -> String {
+    pattern
+        .chars()
+        .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+        .collect()
+}
+
+// If the element of the pattern at p matches the character, return where the next one starts
+fn match_char(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match *pattern.get(p)? {
+        '?' => Some(p + 1),
+        '\\' => match pattern.get(p + 1) {
+            Some(escaped) => (*escaped == c).then(|| p + 2),
+            None => (c == '\\').then(|| p + 1),
+        },
+        '[' => match class_end(pattern, p) {
+            Some(end) => match_class(&pattern[p + 1..end], c).then(|| end + 1),
+            None => (c == '[').then(|| p + 1),
+        },
+        literal => (literal == c).then(|| p + 1),
+    }
+}
+
+// Position of the ] closing the class opened at p - A ] right after [ or [! is a member
+fn class_end(pattern: &[char], p: usize) -> Option<usize> {
+    let mut i = p + 1;
+    if matches!(pattern.get(i), Some('!') | Some('^')) {
+        i += 1;
+    }
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    while let Some(c) = pattern.get(i) {
+        match c {
+            ']' => return Some(i),
+            '\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+// Members of the class sit between the brackets
+fn match_class(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!') | Some('^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        let low = match class[i] {
+            '\\' if i + 1 < class.len() => {
+                i += 1;
+                class[i]
+            }
+            low => low,
+        };
+        if class.get(i + 1) == Some(&'-') && i + 2 < class.len() {
+            matched |= low <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= low == c;
+            i += 1;
+        }
+    }
+    matched != negated
+}
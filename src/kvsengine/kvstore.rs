@@ -1,8 +1,11 @@
 use crate::errors::*;
 use crate::kvsengine::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::{TryFrom, TryInto};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
@@ -11,427 +14,1875 @@ use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use tracing::{debug, error};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
-//To store approx 10 records -- Goal is to see if partitionning
-// is working properly. In real world we could go up to 3 or 4 gygabytes easily
-const MAX_SIZE_THRESHOLD: u64 = 28 * 10;
+mod batch;
+mod compaction;
+mod migrate;
+mod options;
+mod scan;
+pub use batch::WriteBatch;
+pub use options::{CompactionPolicy, KvStoreOptions, SyncPolicy};
+pub use scan::KvScan;
 
-#[derive(Deserialize, Serialize)]
+// Name of the index file of the active data file
+const INDEX_FILE: &str = "kvindex.idx";
+
+// Name of the manifest file written by compaction
+const MANIFEST_FILE: &str = "kvmanifest";
+
+// Data, index and hint files start with a magic and the version of their format :
+// |magic(4)|version(4)|. Every integer written to those files is little-endian
+const FILE_HEADER_SIZE: u64 = 8;
+
+// Version of the format of the data, index and hint files
+const FORMAT_VERSION: u32 = 1;
+
+const DATA_FILE_MAGIC: &[u8; 4] = b"KVSD";
+const INDEX_FILE_MAGIC: &[u8; 4] = b"KVSI";
+const HINT_FILE_MAGIC: &[u8; 4] = b"KVSH";
+
+// Every record starts with a fixed header followed by the bytes of the key and of the value :
+// |magic(2)|version(1)|flags(1)|key length(4)|value length(4)|timestamp(8)|expires_at(8)|crc(4)|
+// The CRC32 covers the rest of the header, the key and the value
+const RECORD_HEADER_SIZE: u64 = 32;
+
+// First bytes of every record
+const RECORD_MAGIC: u16 = 0x4b56;
+
+// Version of the record layout
+const RECORD_VERSION: u8 = 1;
+
+// Flags of the record header
+const FLAG_VALUE: u8 = 0x01;
+const FLAG_EXPIRES: u8 = 0x02;
+const FLAG_BEGIN: u8 = 0x04;
+const FLAG_COMMIT: u8 = 0x08;
+
+// Records of a write batch are framed by a Begin and a Commit marker
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+enum BatchMarker {
+    Begin,
+    Commit,
+}
+
+// A record without value is a tombstone : It marks the key as removed
 struct KvRecord {
-    key: String,
-    value: String,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    // Milliseconds since the epoch
+    timestamp: u64,
+    // Milliseconds since the epoch after which the key is gone - None if it never expires
+    expires_at: Option<u64>,
+    // Marker records have neither key nor value
+    marker: Option<BatchMarker>,
 }
 
 impl KvRecord {
-    pub fn new(key: String, value: String) -> KvRecord {
-        KvRecord { key, value }
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> KvRecord {
+        KvRecord {
+            key,
+            value: Some(value),
+            timestamp: now_timestamp(),
+            expires_at: None,
+            marker: None,
+        }
+    }
+
+    pub fn expiring(key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> KvRecord {
+        let timestamp = now_timestamp();
+        KvRecord {
+            key,
+            value: Some(value),
+            timestamp,
+            expires_at: Some(timestamp.saturating_add(ttl.as_millis() as u64)),
+            marker: None,
+        }
+    }
+
+    pub fn tombstone(key: Vec<u8>) -> KvRecord {
+        KvRecord {
+            key,
+            value: None,
+            timestamp: now_timestamp(),
+            expires_at: None,
+            marker: None,
+        }
+    }
+
+    pub fn marker(marker: BatchMarker) -> KvRecord {
+        KvRecord {
+            key: Vec::new(),
+            value: None,
+            timestamp: now_timestamp(),
+            expires_at: None,
+            marker: Some(marker),
+        }
     }
 }
 
+// The record length only counts the key and the value, the header comes on top of it
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct KvIndex {
-    key: String,
+    key: Vec<u8>,
     file_number: u64,
     record_offset: u64,
     record_length: u64,
+    tombstone: bool,
+    timestamp: u64,
+    expires_at: Option<u64>,
+    // Markers only go to the index file, never to the index map
+    marker: Option<BatchMarker>,
 }
 
 impl KvIndex {
-    pub fn new(key: String, file_number: u64, record_offset: u64, record_length: u64) -> KvIndex {
+    pub fn from_record(
+        record: &KvRecord,
+        file_number: u64,
+        record_offset: u64,
+        record_length: u64,
+    ) -> KvIndex {
         KvIndex {
-            key,
+            key: record.key.clone(),
             file_number,
             record_offset,
             record_length,
+            tombstone: record.value.is_none() && record.marker.is_none(),
+            timestamp: record.timestamp,
+            expires_at: record.expires_at,
+            marker: record.marker,
         }
     }
+
+    pub fn from_hint(hint: KvHint, file_number: u64) -> KvIndex {
+        KvIndex {
+            key: hint.key,
+            file_number,
+            record_offset: hint.record_offset,
+            record_length: hint.record_length,
+            tombstone: hint.tombstone,
+            timestamp: hint.timestamp,
+            expires_at: hint.expires_at,
+            marker: None,
+        }
+    }
+
+    // End of the record in its data file, header included
+    fn record_end(&self) -> u64 {
+        self.record_offset + RECORD_HEADER_SIZE + self.record_length
+    }
+
+    // An expired key is absent, whether it was evicted from the index map yet or not
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// Entry of a hint file : Where the last record of a key sits in a sealed data file.
+// The file number is given by the name of the hint file
+#[derive(Deserialize, Serialize)]
+struct KvHint {
+    key: Vec<u8>,
+    record_offset: u64,
+    record_length: u64,
+    timestamp: u64,
+    tombstone: bool,
+    expires_at: Option<u64>,
+}
+
+impl From<&KvIndex> for KvHint {
+    fn from(index: &KvIndex) -> KvHint {
+        KvHint {
+            key: index.key.clone(),
+            record_offset: index.record_offset,
+            record_length: index.record_length,
+            timestamp: index.timestamp,
+            tombstone: index.tombstone,
+            expires_at: index.expires_at,
+        }
+    }
+}
+
+fn write_file_header<W: Write>(writer: &mut W, magic: &[u8; 4]) -> Result<()> {
+    writer.write_all(magic)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Read the header of a file - Return the version of its format, None if the magic is not there
+fn read_file_header<R: Read>(reader: &mut R, magic: &[u8; 4]) -> Result<Option<u32>> {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != magic {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_le_bytes([
+        header[4], header[5], header[6], header[7],
+    ])))
+}
+
+/// Read the header of a file and refuse any other format than the current one
+fn check_file_header<R: Read>(reader: &mut R, magic: &[u8; 4], file: &Path) -> Result<()> {
+    match read_file_header(reader, magic)? {
+        Some(FORMAT_VERSION) => Ok(()),
+        Some(version) => Err(KvsError::UnsupportedVersion {
+            file: file.to_path_buf(),
+            version,
+        }),
+        None => Err(KvsError::LegacyFormat(file.to_path_buf())),
+    }
+}
+
+/// Check the format of a data file before the store is opened.
+/// Files of older formats must go through KvStore::migrate, files of newer ones are refused
+fn check_data_file(data_file: &Path) -> Result<()> {
+    match migrate::file_format(data_file)? {
+        migrate::FileFormat::Current | migrate::FileFormat::Empty => Ok(()),
+        migrate::FileFormat::LegacyJson
+        | migrate::FileFormat::Json
+        | migrate::FileFormat::Unversioned => {
+            error!("{} was written by an older version", data_file.display());
+            Err(KvsError::LegacyFormat(data_file.to_path_buf()))
+        }
+        migrate::FileFormat::Version(version) => {
+            error!(
+                "{} has format version {} - This version reads {}",
+                data_file.display(),
+                version,
+                FORMAT_VERSION
+            );
+            Err(KvsError::UnsupportedVersion {
+                file: data_file.to_path_buf(),
+                version,
+            })
+        }
+    }
+}
+
+/// Open a data file to append records to it. A new file gets its header first, and so does
+/// a file torn before its header was complete. Return the writer with the end of the file
+fn open_data_file(data_file: &Path) -> Result<(BufWriter<File>, u64)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(data_file)?;
+    let mut file_len = file.metadata()?.len();
+    if file_len < FILE_HEADER_SIZE {
+        file.set_len(0)?;
+        write_file_header(&mut file, DATA_FILE_MAGIC)?;
+        file_len = FILE_HEADER_SIZE;
+    }
+    Ok((BufWriter::new(file), file_len))
+}
+
+/// Write a size prefixed bincode entry - This is the layout of index and hint files
+fn write_entry<W: Write, T: Serialize>(writer: &mut W, entry: &T) -> Result<()> {
+    let serialis = bincode::serialize(entry)?;
+    writer.write_all(&(serialis.len() as u64).to_le_bytes())?;
+    writer.write_all(&serialis)?;
+    Ok(())
+}
+
+/// Write a record with its header - Return the length of its key and value
+fn write_record<W: Write>(writer: &mut W, record: &KvRecord) -> Result<u64> {
+    let key = record.key.as_slice();
+    let value = record.value.as_deref().unwrap_or_default();
+    let mut flags = 0;
+    if record.value.is_some() {
+        flags |= FLAG_VALUE;
+    }
+    if record.expires_at.is_some() {
+        flags |= FLAG_EXPIRES;
+    }
+    match record.marker {
+        Some(BatchMarker::Begin) => flags |= FLAG_BEGIN,
+        Some(BatchMarker::Commit) => flags |= FLAG_COMMIT,
+        None => (),
+    }
+    let key_length = u32::try_from(key.len()).map_err(|_| KvsError::TooLarge)?;
+    let value_length = u32::try_from(value.len()).map_err(|_| KvsError::TooLarge)?;
+
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    header[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    header[2] = RECORD_VERSION;
+    header[3] = flags;
+    header[4..8].copy_from_slice(&key_length.to_le_bytes());
+    header[8..12].copy_from_slice(&value_length.to_le_bytes());
+    header[12..20].copy_from_slice(&record.timestamp.to_le_bytes());
+    header[20..28].copy_from_slice(&record.expires_at.unwrap_or(0).to_le_bytes());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..28]);
+    hasher.update(key);
+    hasher.update(value);
+    header[28..32].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+    writer.write_all(&header)?;
+    writer.write_all(key)?;
+    writer.write_all(value)?;
+    Ok((key.len() + value.len()) as u64)
+}
+
+// Fixed header of a record, as read from a data file
+struct RecordHeader {
+    bytes: [u8; RECORD_HEADER_SIZE as usize],
+}
+
+impl RecordHeader {
+    /// Read the header of a record
+    fn read<R: Read>(reader: &mut R) -> Result<RecordHeader> {
+        let mut bytes = [0u8; RECORD_HEADER_SIZE as usize];
+        reader.read_exact(&mut bytes)?;
+        Ok(RecordHeader { bytes })
+    }
+
+    // False if the bytes are not the header of a record this version can decode
+    fn is_valid(&self) -> bool {
+        u16::from_le_bytes(self.field(0)) == RECORD_MAGIC && self.bytes[2] == RECORD_VERSION
+    }
+
+    fn field<const N: usize>(&self, offset: usize) -> [u8; N] {
+        self.bytes[offset..offset + N]
+            .try_into()
+            .expect("Field out of the header")
+    }
+
+    fn key_length(&self) -> u64 {
+        u32::from_le_bytes(self.field(4)) as u64
+    }
+
+    // Length of the key and of the value
+    fn record_length(&self) -> u64 {
+        self.key_length() + u32::from_le_bytes(self.field(8)) as u64
+    }
+
+    // True if the key and the value read after the header match the checksum
+    fn verify(&self, payload: &[u8]) -> bool {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.bytes[..28]);
+        hasher.update(payload);
+        hasher.finalize() == u32::from_le_bytes(self.field(28))
+    }
+
+    /// Build the record from its key and value
+    fn decode(&self, mut payload: Vec<u8>) -> Result<KvRecord> {
+        let flags = self.bytes[3];
+        let value = payload.split_off(self.key_length() as usize);
+        let marker = if flags & FLAG_BEGIN != 0 {
+            Some(BatchMarker::Begin)
+        } else if flags & FLAG_COMMIT != 0 {
+            Some(BatchMarker::Commit)
+        } else {
+            None
+        };
+        Ok(KvRecord {
+            key: payload,
+            value: match flags & FLAG_VALUE {
+                0 => None,
+                _ => Some(value),
+            },
+            timestamp: u64::from_le_bytes(self.field(12)),
+            expires_at: match flags & FLAG_EXPIRES {
+                0 => None,
+                _ => Some(u64::from_le_bytes(self.field(20))),
+            },
+            marker,
+        })
+    }
+}
+
+/// Read the record an index points to in its data file.
+/// The length is checked against the index before reading and the checksum after
+fn read_record_at<R: Read + Seek>(
+    reader: &mut R,
+    directory: &Path,
+    index: &KvIndex,
+) -> Result<KvRecord> {
+    let offset = index.record_offset;
+    reader.seek(SeekFrom::Start(offset))?;
+    let header = RecordHeader::read(reader)?;
+    let corrupted = |reason| {
+        error!(
+            "{} at offset {} of file_{}.bdd",
+            reason, offset, index.file_number
+        );
+        KvsError::Corrupted {
+            file: data_file_path(directory, index.file_number),
+            offset,
+        }
+    };
+    if !header.is_valid() {
+        return Err(corrupted("Unreadable header"));
+    }
+    // A damaged length must not size the buffer
+    if header.record_length() != index.record_length {
+        return Err(corrupted("Length mismatch"));
+    }
+    let mut record_bytes = vec![0u8; header.record_length() as usize];
+    reader.read_exact(&mut record_bytes)?;
+    if !header.verify(&record_bytes) {
+        return Err(corrupted("Checksum mismatch"));
+    }
+    header.decode(record_bytes)
+}
+
+fn data_file_path(directory: &Path, file_number: u64) -> PathBuf {
+    directory.join(format!("file_{}.bdd", file_number))
+}
+
+fn hint_file_path(directory: &Path, file_number: u64) -> PathBuf {
+    directory.join(format!("file_{}.hint", file_number))
+}
+
+/// Write the hint file of a sealed data file.
+/// It is written aside and renamed so that a crash never leaves half a hint file
+fn write_hint_file(
+    directory: &Path,
+    file_number: u64,
+    entries: &BTreeMap<Vec<u8>, KvIndex>,
+) -> Result<()> {
+    let hint_file = hint_file_path(directory, file_number);
+    let tmp_file = hint_file.with_extension("hint.tmp");
+    let mut hint_writer = BufWriter::new(File::create(&tmp_file)?);
+    write_file_header(&mut hint_writer, HINT_FILE_MAGIC)?;
+    for index in entries.values() {
+        write_entry(&mut hint_writer, &KvHint::from(index))?;
+    }
+    hint_writer.flush()?;
+    hint_writer.get_ref().sync_all()?;
+    fs::rename(&tmp_file, &hint_file)?;
+    debug!(
+        "Hint file written for file_{}.bdd with {} entries",
+        file_number,
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Read the next size prefixed bincode entry - None when the end of the file is reached.
+/// The buffer grows with the bytes actually read : A damaged size never allocates more than
+/// what is left of the file, and an entry cut short is an error
+fn read_entry<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut rl_bytes = [0u8; 8];
+    match reader.read_exact(&mut rl_bytes) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(KvsError::Io(err)),
+    }
+    let entry_length = u64::from_le_bytes(rl_bytes);
+    let mut entry_bytes = Vec::new();
+    reader.take(entry_length).read_to_end(&mut entry_bytes)?;
+    if (entry_bytes.len() as u64) < entry_length {
+        return Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(Some(bincode::deserialize(entry_bytes.as_slice())?))
 }
 
 /// Main structure that hold our key/value store
-/// Everything is based around a hashmap of bufreader for fast access
-/// And 2 writers : One for the active log file and an other for the active index file
+/// A KvStore is a handle : Clones share the same index and the same writer and can be sent
+/// to other threads. Every clone opens its own readers, at most max_readers_per_handle of
+/// them, so that gets never wait for each other while one writer appends to the active file.
 pub struct KvStore {
-    active_file_number: u64,
+    shared: Arc<SharedStore>,
+    readers: RefCell<HashMap<u64, BufReader<File>>>,
+    // Readers below this generation were closed - See SharedStore::min_generation
+    readers_floor: Cell<u64>,
+}
+
+// State shared by every clone of a store
+struct SharedStore {
     base_directory: PathBuf,
-    active_file_writer: BufWriter<File>,
-    index_file_writer: BufWriter<File>,
-    index_map: BTreeMap<String, KvIndex>,
-    readers: HashMap<u64, BufReader<File>>,
+    options: KvStoreOptions,
+    index_map: RwLock<BTreeMap<Vec<u8>, KvIndex>>,
+    writer: Mutex<KvWriter>,
+    // Where readers find the active file and how much of it left the buffer of the writer
+    active_file_number: AtomicU64,
+    flushed_end: AtomicU64,
+    // Data files below this generation were deleted by a compaction
+    min_generation: AtomicU64,
+    // Lets a background compaction install itself once merged
+    this: Weak<SharedStore>,
+}
+
+// Everything needed to append to the store - Writers are None when the store is read-only
+struct KvWriter {
+    active_file_number: u64,
+    // End of the active file, buffered bytes included
+    active_end: u64,
+    active_file_writer: Option<BufWriter<File>>,
+    index_file_writer: Option<BufWriter<File>>,
+    // Last entry of each key in the active file, tombstones included - Its hint file once sealed
+    active_entries: FileEntries,
+    // One entry per data file of the store
+    file_stats: HashMap<u64, FileStats>,
+    compaction_policy: CompactionPolicy,
+    compaction: Option<CompactionTask>,
+    // Writes since the last sync - Used by the sync policy
+    writes_since_sync: u64,
+}
+
+// Live bytes are the records the index points at, everything else in the file is dead
+#[derive(Default, Debug)]
+struct FileStats {
+    live_bytes: u64,
+    dead_bytes: u64,
 }
 
-fn search_bdd_files(directory: &PathBuf) -> Result<Vec<u64>> {
-    let bdd_files = fs::read_dir(&directory)?
+// A compaction running in a background thread
+struct CompactionTask {
+    receiver: mpsc::Receiver<Result<compaction::CompactionOutput>>,
+    // Every data file that existed when the compaction started
+    old_files: Vec<u64>,
+    // Generations reserved for the merged files
+    first_generation: u64,
+    last_generation: u64,
+}
+
+// Generation number of a file named file_XX.ext
+fn file_number(file: &Path) -> Option<u64> {
+    file.file_stem()
+        .and_then(OsStr::to_str)
+        .map(|name| name.trim_start_matches("file_")) // Yield an Option(String)
+        .and_then(|number| number.parse::<u64>().ok())
+}
+
+fn search_files(directory: &Path, extension: &str) -> Result<Vec<u64>> {
+    let files = fs::read_dir(directory)?
         .flat_map(|x| -> Result<_> { Ok(x?.path()) })
-        .filter(|file| file.is_file() && file.extension() == Some("bdd".as_ref()))
-        .flat_map(|file| {
-            file.file_stem()
-                .and_then(OsStr::to_str)
-                .map(|name| name.trim_start_matches("file_")) // Yield an Option(String)
-                .map(str::parse::<u64>) //Yield an Option(Option(u64) )
-        }) //Yield an Option(String) -- One level of Option has been removed by the "flat"
-        .flatten() //Extract the value
+        .filter(|file| file.is_file() && file.extension() == Some(extension.as_ref()))
+        .flat_map(|file| file_number(&file)) //Keep the files with a generation number
         .collect(); //Consume the iterator
-    return Ok(bdd_files);
-}
-
-fn init_readers(directory: &PathBuf) -> Result<(HashMap<u64, BufReader<File>>, u64)> {
-    let mut my_readers: HashMap<u64, BufReader<File>> = HashMap::new();
-    let bdd_files = search_bdd_files(directory);
-    let mut max_gen: u64 = 0;
-    match bdd_files {
-        Ok(files) => {
-            for file in files {
-                if file > max_gen {
-                    max_gen = file;
-                }
-                let mut file_path = directory.clone();
-                file_path.push(format!("file_{}.bdd", file));
-                //Create a buffered Reader and insert it in a Hashmap
-                let bdd_reader = BufReader::new(File::open(&file_path)?);
-                my_readers.insert(file, bdd_reader);
+    Ok(files)
+}
+
+fn search_bdd_files(directory: &Path) -> Result<Vec<u64>> {
+    search_files(directory, "bdd")
+}
+
+// The manifest is written once the output of a compaction is complete.
+// Every data file below min_generation has been compacted and can be deleted.
+#[derive(Deserialize, Serialize)]
+struct KvManifest {
+    min_generation: u64,
+}
+
+fn read_manifest(directory: &Path) -> Result<KvManifest> {
+    match fs::read(directory.join(MANIFEST_FILE)) {
+        Ok(manifest) => Ok(serde_json::from_slice(manifest.as_slice())?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(KvManifest { min_generation: 0 }),
+        Err(err) => Err(KvsError::Io(err)),
+    }
+}
+
+/// Replace the manifest atomically : It is written aside, synced and renamed
+fn write_manifest(directory: &Path, manifest: &KvManifest) -> Result<()> {
+    let manifest_file = directory.join(MANIFEST_FILE);
+    let tmp_file = manifest_file.with_extension("tmp");
+    let mut writer = File::create(&tmp_file)?;
+    writer.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+    writer.sync_all()?;
+    fs::rename(&tmp_file, &manifest_file)?;
+    // The rename itself is only durable once the directory is synced
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+/// Remove what an interrupted compaction or hint write left behind :
+/// Files below the generation of the manifest, unfinished compaction files and temporary files
+fn clean_directory(directory: &Path) -> Result<()> {
+    let manifest = read_manifest(directory)?;
+    for entry in fs::read_dir(directory)? {
+        let file = entry?.path();
+        if !file.is_file() {
+            continue;
+        }
+        let obsolete = match file.extension().and_then(OsStr::to_str) {
+            Some("new") | Some("old") | Some("tmp") => true,
+            Some("bdd") | Some("hint") => {
+                file_number(&file).is_some_and(|number| number < manifest.min_generation)
             }
-            return Ok((my_readers, max_gen));
+            _ => false,
+        };
+        if obsolete {
+            debug!("Removing leftover file {}", file.display());
+            fs::remove_file(&file)?;
         }
-        Err(x) => Err(x),
     }
+    Ok(())
 }
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        self.index_file_writer.flush();
-        self.active_file_writer.flush();
+// Last entry of each key in one data file
+type FileEntries = BTreeMap<Vec<u8>, KvIndex>;
+
+// What a scan of a data file found
+struct DataFileScan {
+    // Last entry of each key, tombstones included
+    entries: BTreeMap<Vec<u8>, KvIndex>,
+    // End of the last record that can be trusted, and length of the file
+    valid_end: u64,
+    file_len: u64,
+    // Commit marker ending the file - The index file keeps it to cover the whole file
+    end_marker: Option<KvIndex>,
+}
+
+/// Scan one data file and return the last entry of each key, tombstones included.
+/// Return as well the end of the last valid record along with the length of the file
+/// A record failing its checksum is reported as corrupted, unless it is the last record
+/// of the active file : That is what a torn write looks like.
+/// A batch missing its commit marker is discarded, the valid end is put back before it.
+fn scan_data_file(directory: &Path, file_number: u64, is_active: bool) -> Result<DataFileScan> {
+    let mut entries: BTreeMap<Vec<u8>, KvIndex> = BTreeMap::new();
+    // Offset of the begin marker and entries of the batch being read
+    let mut batch: Option<(u64, BTreeMap<Vec<u8>, KvIndex>)> = None;
+    let mut end_marker: Option<KvIndex> = None;
+    let data_file = data_file_path(directory, file_number);
+    let mut reader = BufReader::new(File::open(&data_file)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    if file_len < FILE_HEADER_SIZE {
+        // Only a read-only store sees a data file torn before its header was complete
+        return Ok(DataFileScan {
+            entries,
+            valid_end: file_len,
+            file_len,
+            end_marker,
+        });
+    }
+    check_file_header(&mut reader, DATA_FILE_MAGIC, &data_file)?;
+    let mut pos: u64 = FILE_HEADER_SIZE;
+    while pos + RECORD_HEADER_SIZE <= file_len {
+        let header = RecordHeader::read(&mut reader)?;
+        if !header.is_valid() {
+            if is_active {
+                warn!(
+                    "Unreadable header at offset {} of file_{}.bdd",
+                    pos, file_number
+                );
+                break;
+            }
+            return Err(KvsError::Corrupted {
+                file: data_file,
+                offset: pos,
+            });
+        }
+        let record_length = header.record_length();
+        let record_end = pos + RECORD_HEADER_SIZE + record_length;
+        if record_end > file_len {
+            warn!(
+                "Incomplete record at offset {} of file_{}.bdd",
+                pos, file_number
+            );
+            break;
+        }
+        let mut record_bytes = vec![0u8; record_length as usize];
+        reader.read_exact(&mut record_bytes)?;
+        if !header.verify(&record_bytes) {
+            if is_active && record_end == file_len {
+                warn!("Torn record at offset {} of file_{}.bdd", pos, file_number);
+                break;
+            }
+            return Err(KvsError::Corrupted {
+                file: data_file,
+                offset: pos,
+            });
+        }
+        let record = match header.decode(record_bytes) {
+            Ok(record) => record,
+            Err(x) => {
+                error!(
+                    "Unreadable record at offset {} of file_{}.bdd : {:?}",
+                    pos, file_number, x
+                );
+                break;
+            }
+        };
+        let index = KvIndex::from_record(&record, file_number, pos, record_length);
+        end_marker = None;
+        match index.marker {
+            Some(BatchMarker::Begin) => {
+                if let Some((begin, _)) = batch {
+                    warn!(
+                        "Batch at offset {} of file_{}.bdd was never committed",
+                        begin, file_number
+                    );
+                }
+                batch = Some((pos, BTreeMap::new()));
+            }
+            Some(BatchMarker::Commit) => {
+                match batch.take() {
+                    Some((_, batch_entries)) => entries.extend(batch_entries),
+                    None => warn!(
+                        "Commit marker without batch at offset {} of file_{}.bdd",
+                        pos, file_number
+                    ),
+                }
+                end_marker = Some(index);
+            }
+            None => {
+                let batch_entries = match &mut batch {
+                    Some((_, batch_entries)) => batch_entries,
+                    None => &mut entries,
+                };
+                batch_entries.insert(index.key.clone(), index);
+            }
+        }
+        pos = record_end;
     }
+    if let Some((begin, batch_entries)) = batch {
+        warn!(
+            "Discarding the uncommitted batch of {} keys at offset {} of file_{}.bdd",
+            batch_entries.len(),
+            begin,
+            file_number
+        );
+        pos = begin;
+    }
+    Ok(DataFileScan {
+        entries,
+        valid_end: pos,
+        file_len,
+        end_marker,
+    })
 }
 
-impl KvStore {
-    fn new(directory: PathBuf) -> KvStore {
-        let mut file: PathBuf = directory.clone();
+fn read_hint_file(directory: &Path, file_number: u64) -> Result<BTreeMap<Vec<u8>, KvIndex>> {
+    let hint_file = hint_file_path(directory, file_number);
+    let mut hint_reader = BufReader::new(File::open(&hint_file)?);
+    check_file_header(&mut hint_reader, HINT_FILE_MAGIC, &hint_file)?;
+    let mut entries: BTreeMap<Vec<u8>, KvIndex> = BTreeMap::new();
+    while let Some(hint) = read_entry::<_, KvHint>(&mut hint_reader)? {
+        entries.insert(hint.key.clone(), KvIndex::from_hint(hint, file_number));
+    }
+    Ok(entries)
+}
 
-        let mut idx_file: PathBuf = directory.clone();
-        idx_file.push("kvindex.idx");
-        let index_writer = OpenOptions::new()
+/// Rewrite kvindex.idx with the given entries - Return the writer to append to it.
+/// The commit marker ending the data file goes last, if any
+fn write_index_file(
+    directory: &Path,
+    entries: &BTreeMap<Vec<u8>, KvIndex>,
+    end_marker: Option<&KvIndex>,
+) -> Result<BufWriter<File>> {
+    let mut index_file_writer = BufWriter::new(
+        OpenOptions::new()
+            .truncate(true)
             .write(true)
             .create(true)
-            .read(true)
-            .append(true)
-            .open(idx_file);
-        if let Ok(index_writer) = index_writer {
-            if let Ok((readers, max_file)) = init_readers(&directory) {
-                file.push(format!("file_{}.bdd", max_file));
-                if let Ok(curr_file) = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .write(true)
-                    .open(&file)
-                {
-                    KvStore {
-                        active_file_number: max_file as u64,
-                        base_directory: directory.clone(),
-                        active_file_writer: BufWriter::new(curr_file),
-                        index_map: BTreeMap::new(),
-                        index_file_writer: BufWriter::new(index_writer),
-                        readers: readers,
+            .open(directory.join(INDEX_FILE))?,
+    );
+    write_file_header(&mut index_file_writer, INDEX_FILE_MAGIC)?;
+    for index in entries.values().chain(end_marker) {
+        write_entry(&mut index_file_writer, index)?;
+    }
+    index_file_writer.flush()?;
+    Ok(index_file_writer)
+}
+
+/// Replay kvindex.idx - Only the entries of the active file are kept.
+/// Return them with the end of the last record the index file knows about.
+/// Entries of a batch only count once its commit marker is found.
+/// An index file of another format is ignored, so that it is rebuilt
+fn load_index(
+    directory: &Path,
+    active_file_number: u64,
+) -> Result<(BTreeMap<Vec<u8>, KvIndex>, u64)> {
+    let mut entries: BTreeMap<Vec<u8>, KvIndex> = BTreeMap::new();
+    let mut batch: Option<Vec<KvIndex>> = None;
+    let index_file = directory.join(INDEX_FILE);
+    let mut idx_file = match File::open(&index_file) {
+        Ok(idx_file) => BufReader::new(idx_file),
+        Err(z) => {
+            error!("Error when opening indexfile {:?}", z);
+            return Ok((entries, 0));
+        }
+    };
+    if let Err(x) = check_file_header(&mut idx_file, INDEX_FILE_MAGIC, &index_file) {
+        warn!("Ignoring the index file : {:?}", x);
+        return Ok((entries, 0));
+    }
+    // The header of the data file is covered by any index file
+    let mut indexed_end: u64 = FILE_HEADER_SIZE;
+    loop {
+        // A truncated or unreadable index file simply ends the replay,
+        // the comparison with the length of the active file will catch it
+        match read_entry::<_, KvIndex>(&mut idx_file) {
+            Ok(Some(index)) => {
+                if index.file_number != active_file_number {
+                    continue;
+                }
+                match (index.marker, &mut batch) {
+                    (Some(BatchMarker::Begin), _) => batch = Some(Vec::new()),
+                    (Some(BatchMarker::Commit), _) => {
+                        indexed_end = indexed_end.max(index.record_end());
+                        for index in batch.take().unwrap_or_default() {
+                            indexed_end = indexed_end.max(index.record_end());
+                            entries.insert(index.key.clone(), index);
+                        }
+                    }
+                    (None, Some(batch_entries)) => batch_entries.push(index),
+                    (None, None) => {
+                        indexed_end = indexed_end.max(index.record_end());
+                        entries.insert(index.key.clone(), index);
                     }
-                } else {
-                    panic!("Could not open current file...");
                 }
-            } else {
-                panic!("Readers were not initialized...");
             }
+            Ok(None) => break,
+            Err(x) => {
+                error!("Error during deserialize : {:?}", x);
+                break;
+            }
+        }
+    }
+    Ok((entries, indexed_end))
+}
+
+/// Apply the entries of one data file to the index map.
+/// Files must be applied in generation order as tombstones remove keys set in older files.
+/// Expired entries remove their key as well
+fn apply_entries(index_map: &mut BTreeMap<Vec<u8>, KvIndex>, entries: BTreeMap<Vec<u8>, KvIndex>) {
+    let now = now_timestamp();
+    for (key, index) in entries {
+        if index.tombstone || index.is_expired(now) {
+            index_map.remove(&key);
         } else {
-            panic!("Index_writer could be created");
+            index_map.insert(key, index);
         }
     }
+}
 
-    /// Synchronize the index map to a file.
-    pub fn sync_index(&mut self) -> Result<()> {
-        let mut index_file_path: PathBuf = self.base_directory.clone();
-        index_file_path.push("kvindex.idx");
-        self.index_file_writer = BufWriter::new(
-            OpenOptions::new()
-                .truncate(true)
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&index_file_path)?,
+fn load_sealed_file(
+    directory: &Path,
+    file_number: u64,
+    read_only: bool,
+) -> Result<BTreeMap<Vec<u8>, KvIndex>> {
+    match read_hint_file(directory, file_number) {
+        Ok(entries) => Ok(entries),
+        Err(x) => {
+            warn!(
+                "No usable hint file for file_{}.bdd ({:?}) - Scanning the data file",
+                file_number, x
+            );
+            let scan = scan_data_file(directory, file_number, false)?;
+            if !read_only {
+                write_hint_file(directory, file_number, &scan.entries)?;
+            }
+            Ok(scan.entries)
+        }
+    }
+}
+
+/// Load the entries of the active file from kvindex.idx.
+/// If kvindex.idx does not cover the whole file, the file is scanned instead : A torn write
+/// at its end is cut off and kvindex.idx is written again, its writer is returned then
+fn load_active_file(
+    directory: &Path,
+    active_file_number: u64,
+    read_only: bool,
+) -> Result<(FileEntries, Option<BufWriter<File>>)> {
+    let (entries, indexed_end) = load_index(directory, active_file_number)?;
+    let data_file = data_file_path(directory, active_file_number);
+    let file_len = fs::metadata(&data_file)?.len();
+    if indexed_end == file_len {
+        return Ok((entries, None));
+    }
+
+    warn!("Index file is missing or stale - Rebuilding it from the active file");
+    debug!(
+        "file_{}.bdd is {} bytes long but only {} bytes are indexed",
+        active_file_number, file_len, indexed_end
+    );
+    let scan = scan_data_file(directory, active_file_number, true)?;
+    if read_only {
+        return Ok((scan.entries, None));
+    }
+    let (valid_end, file_len) = (scan.valid_end, scan.file_len);
+    if valid_end < file_len {
+        // A torn write at the end of the active file would hide every record appended
+        // after it, so the garbage is cut off
+        warn!(
+            "Truncating file_{}.bdd from {} to {} bytes",
+            active_file_number, file_len, valid_end
         );
+        OpenOptions::new()
+            .write(true)
+            .open(&data_file)?
+            .set_len(valid_end)?;
+    }
+    let index_file_writer = write_index_file(directory, &scan.entries, scan.end_marker.as_ref())?;
+    Ok((scan.entries, Some(index_file_writer)))
+}
 
-        for (_, index) in &self.index_map {
-            let serialis = serde_json::to_string(&index)?;
-            let size_of = serialis.len().to_ne_bytes();
-            std::io::Write::by_ref(&mut self.index_file_writer).write(&size_of)?;
-            std::io::Write::by_ref(&mut self.index_file_writer).write(serialis.as_bytes())?;
+impl Clone for KvStore {
+    /// The clone shares the store but opens its own readers
+    fn clone(&self) -> KvStore {
+        KvStore {
+            shared: Arc::clone(&self.shared),
+            readers: RefCell::new(HashMap::new()),
+            readers_floor: Cell::new(0),
+        }
+    }
+}
+
+/// Evict expired keys every interval until the store is dropped
+fn spawn_sweeper(shared: &Arc<SharedStore>, interval: Duration) {
+    let shared = Arc::downgrade(shared);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        if let Err(x) = shared.sweep_expired() {
+            error!("Could not evict the expired keys : {:?}", x);
+        }
+    });
+}
+
+/// Sync the pending writes every interval until the store is dropped
+fn spawn_syncer(shared: &Arc<SharedStore>, interval: Duration) {
+    let shared = Arc::downgrade(shared);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        if let Err(x) = shared.sync_pending() {
+            error!("Could not sync the pending writes : {:?}", x);
+        }
+    });
+}
+
+impl Drop for SharedStore {
+    fn drop(&mut self) {
+        // Nobody is left to handle the errors, they are logged at least
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(task) = writer.compaction.take() {
+            if let Err(x) = self.finish_compaction(&mut writer, task) {
+                error!("Could not install the compaction on drop : {:?}", x);
+            }
+        }
+        if let Err(x) = self.flush(&mut writer) {
+            error!("Could not flush the store on drop : {:?}", x);
         }
-        Ok(())
     }
+}
 
+impl KvStore {
     /// Open a store directory - A store directory contains every files required to operate
-    /// 0..N file_XX.bdd --> Containing datas as |Sizeofrecord(8bytes)|Record(N bytes)|...
-    /// 0..1 kvindex.idx file -> Containing the index as described in the bitcask paper
-    /// The function will count how many files there is in the directory and then load the index
-    /// for each index it will quickly check that the file exists.
-    /// And finaly we will check if each data file is indexed, if not it will be indexed.
+    /// 0..N file_XX.bdd --> Containing datas as |File header|Header(32 bytes)|Key|Value|...
+    /// 0..N file_XX.hint --> One per sealed data file, where the last record of each key is
+    /// 0..1 kvindex.idx file -> Containing the index of the active file
+    /// 0..1 kvmanifest file -> Written by compaction, data files below its generation are deleted
+    /// Leftovers of an interrupted compaction are removed before anything is loaded.
+    /// Sealed files are loaded from their hint file, so only the active file may be scanned.
+    /// A sealed file without a usable hint file is scanned and its hint file is written.
+    /// If kvindex.idx does not cover the active file, the active file is scanned and a fresh
+    /// kvindex.idx is written.
     /// Even if the operation can be long at time it should be performed only one when running in
     /// server <-> client mode
+    /// The store is opened with the default options, see KvStoreOptions for the others
     pub fn open<P: Into<PathBuf>>(directory: P) -> Result<KvStore> {
-        let mut pos: u64 = 0;
-        let mut mypath: PathBuf = directory.into();
-        let mut store: KvStore = KvStore::new(mypath.clone());
-        mypath.push("kvindex.idx");
-        match File::open(&mypath) {
-            Ok(mut idx_file) => {
-                let mut rl_bytes = [0u8; 8];
-                idx_file.seek(SeekFrom::Start(pos))?;
-                let mut bcontinue = true;
-                while bcontinue {
-                    if let Ok(nb_bytes_read) = std::io::Read::by_ref(&mut idx_file)
-                        .take(8)
-                        .read(&mut rl_bytes)
-                    {
-                        if nb_bytes_read == 8 {
-                            let size_of_record = i64::from_ne_bytes(rl_bytes);
-                            if size_of_record < 0 {
-                                pos += size_of_record.abs() as u64;
-                            } else {
-                                let mut record_bytes = vec![];
-                                std::io::Read::by_ref(&mut idx_file)
-                                    .take(size_of_record as u64)
-                                    .read_to_end(&mut record_bytes)?;
-                                match serde_json::from_slice::<KvIndex>(record_bytes.as_slice()) {
-                                    Ok(index) => {
-                                        store.index_map.insert(index.key.clone(), index);
-                                    }
-                                    Err(x) => {
-                                        error!("Error during deserialize : {:?}", x);
-                                    }
-                                }
-                            }
-                        } else {
-                            bcontinue = false;
-                        }
-                    } else {
-                        bcontinue = false;
-                    }
-                }
-            }
-            Err(z) => {
-                error!("Error when opening indexfile {:?}", z);
+        KvStore::open_with_options(directory, KvStoreOptions::default())
+    }
+
+    /// A read-only store leaves the directory as it is : Nothing is cleaned, truncated or
+    /// rewritten and missing hint and index files are only rebuilt in memory
+    fn open_with_options<P: Into<PathBuf>>(
+        directory: P,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        options.validate()?;
+        let directory: PathBuf = directory.into();
+        let read_only = options.read_only;
+        if options.create_dirs && !read_only {
+            fs::create_dir_all(&directory)?;
+        }
+        if !read_only {
+            clean_directory(&directory)?;
+        }
+
+        // Files below the manifest are only left behind in read-only mode, they are skipped
+        let min_generation = read_manifest(&directory)?.min_generation;
+        let mut file_stats: HashMap<u64, FileStats> = HashMap::new();
+        for file_number in search_bdd_files(&directory)? {
+            if file_number >= min_generation {
+                check_data_file(&data_file_path(&directory, file_number))?;
+                file_stats.insert(file_number, FileStats::default());
             }
+        }
+        let active_file_number = file_stats.keys().copied().max().unwrap_or(min_generation);
+
+        let mut sealed_files: Vec<u64> = file_stats
+            .keys()
+            .copied()
+            .filter(|file_number| *file_number != active_file_number)
+            .collect();
+        sealed_files.sort_unstable();
+        let mut index_map: BTreeMap<Vec<u8>, KvIndex> = BTreeMap::new();
+        for file_number in sealed_files {
+            let entries = load_sealed_file(&directory, file_number, read_only)?;
+            apply_entries(&mut index_map, entries);
+        }
+
+        let mut writer = KvWriter {
+            active_file_number,
+            active_end: 0,
+            active_file_writer: None,
+            index_file_writer: None,
+            active_entries: BTreeMap::new(),
+            file_stats,
+            compaction_policy: options.compaction,
+            compaction: None,
+            writes_since_sync: 0,
         };
+        if !read_only {
+            // The active file may be brand new
+            let (active_file_writer, _) =
+                open_data_file(&data_file_path(&directory, active_file_number))?;
+            writer.active_file_writer = Some(active_file_writer);
+            writer.file_stats.entry(active_file_number).or_default();
+        }
+        // Nothing to load when an empty directory is opened read-only
+        if writer.file_stats.contains_key(&active_file_number) {
+            let (entries, index_file_writer) =
+                load_active_file(&directory, active_file_number, read_only)?;
+            if !read_only {
+                writer.active_entries = entries.clone();
+            }
+            apply_entries(&mut index_map, entries);
+            writer.index_file_writer = index_file_writer;
+            writer.active_end = fs::metadata(data_file_path(&directory, active_file_number))?.len();
+        }
+        if !read_only && writer.index_file_writer.is_none() {
+            let index_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(directory.join(INDEX_FILE))?;
+            writer.index_file_writer = Some(BufWriter::new(index_file));
+        }
 
-        return Ok(store);
+        let flushed_end = writer.active_end;
+        let shared = Arc::new_cyclic(|this| SharedStore {
+            base_directory: directory,
+            options,
+            index_map: RwLock::new(index_map),
+            active_file_number: AtomicU64::new(active_file_number),
+            flushed_end: AtomicU64::new(flushed_end),
+            min_generation: AtomicU64::new(min_generation),
+            writer: Mutex::new(writer),
+            this: this.clone(),
+        });
+        shared.compute_stats(&mut *shared.writer.lock()?)?;
+        if shared.options.sweep_interval > 0 {
+            spawn_sweeper(
+                &shared,
+                Duration::from_millis(shared.options.sweep_interval),
+            );
+        }
+        if let SyncPolicy::Interval(ms) = shared.options.sync {
+            if !shared.options.read_only {
+                spawn_syncer(&shared, Duration::from_millis(ms));
+            }
+        }
+        Ok(KvStore {
+            shared,
+            readers: RefCell::new(HashMap::new()),
+            readers_floor: Cell::new(min_generation),
+        })
     }
 
-    /// Go through the index_map and for each index found : Copy datas from file to a new one.
-    /// In the end remaining file should be filled with active records.
-    pub fn compaction(&mut self) -> Result<()> {
-        // Files from 1 to Active-1 are compacted
-        // This should not be confused with the new file function that simply create a new log file
-        // when the max size is reached.
+    /// Convert a directory written by an older version to the current format version.
+    /// Data files are converted one by one and renamed over the old ones, a directory where
+    /// the migration was interrupted is migrated again. Hint and index files are rebuilt when
+    /// the store is opened at the end. Return the number of records converted
+    pub fn migrate<P: Into<PathBuf>>(directory: P) -> Result<u64> {
+        let directory: PathBuf = directory.into();
+        clean_directory(&directory)?;
+        let migrated = migrate::migrate_directory(&directory)?;
+        KvStore::open(directory)?;
+        Ok(migrated)
+    }
 
-        let mut writers: HashMap<u64, BufWriter<File>> = HashMap::new();
-        let mut new_index_map: BTreeMap<String, KvIndex> = BTreeMap::new();
+    /// Rewrite kvindex.idx from the records of the active file.
+    /// The index file only describes the active file, sealed files are described by hint files
+    pub fn sync_index(&self) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        shared.flush(&mut writer)?;
+        let scan = scan_data_file(&shared.base_directory, writer.active_file_number, true)?;
+        writer.index_file_writer = Some(write_index_file(
+            &shared.base_directory,
+            &scan.entries,
+            scan.end_marker.as_ref(),
+        )?);
+        writer.active_entries = scan.entries;
+        Ok(())
+    }
 
-        for (cle, index) in &self.index_map {
-            if index.file_number == self.active_file_number {
-                //We do not work on the current file
-                let new_index = KvIndex::new(
-                    cle.clone(),
-                    index.file_number,
-                    index.record_offset,
-                    index.record_length,
-                );
-                new_index_map.insert(cle.clone(), new_index);
-                continue;
+    /// Apply every operation of the batch at once.
+    /// Readers see all of them or none, and so does the store opened again after a crash
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        shared.write_batch(&mut writer, batch.records)
+    }
+
+    /// Hand the buffered writes of the data and index files over to the operating system.
+    /// They survive a crash of the process but not a crash of the machine
+    pub fn flush(&self) -> Result<()> {
+        self.shared.flush(&mut *self.shared.writer.lock()?)
+    }
+
+    /// Flush the data and index files and force them to disk
+    pub fn sync(&self) -> Result<()> {
+        self.shared.sync(&mut *self.shared.writer.lock()?)
+    }
+
+    /// Compact every data file of the store and wait for the compaction to be installed.
+    /// A compaction already running in the background is waited for first.
+    /// See the compaction module for how a compaction stays crash-safe.
+    pub fn compaction(&self) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        if let Some(task) = writer.compaction.take() {
+            shared.finish_compaction(&mut writer, task)?;
+        }
+        shared.start_compaction(&mut writer)?;
+        match writer.compaction.take() {
+            Some(task) => shared.finish_compaction(&mut writer, task),
+            None => Ok(()),
+        }
+    }
+
+    /// Choose when compactions are triggered automatically
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) -> Result<()> {
+        self.shared.writer.lock()?.compaction_policy = policy;
+        Ok(())
+    }
+
+    /// Read the record an index entry points at with the readers of this handle.
+    /// The checksum is verified before the record is decoded
+    fn read_record(&self, index: &KvIndex) -> Result<KvRecord> {
+        let shared = &self.shared;
+        if index.file_number == shared.active_file_number.load(Ordering::SeqCst)
+            && index.record_end() > shared.flushed_end.load(Ordering::SeqCst)
+        {
+            // The record is still sitting in the buffer of the writer
+            shared.flush(&mut *shared.writer.lock()?)?;
+        }
+
+        let mut readers = self.readers.borrow_mut();
+        let min_generation = shared.min_generation.load(Ordering::SeqCst);
+        if min_generation > self.readers_floor.get() {
+            // Their files were deleted by a compaction
+            readers.retain(|file_number, _| *file_number >= min_generation);
+            self.readers_floor.set(min_generation);
+        }
+        if !readers.contains_key(&index.file_number) {
+            if readers.len() >= shared.options.max_readers_per_handle {
+                // Any reader will do, the active file is read the most so it is kept if possible
+                let active_file_number = shared.active_file_number.load(Ordering::SeqCst);
+                let evicted = readers
+                    .keys()
+                    .copied()
+                    .find(|file_number| *file_number != active_file_number)
+                    .or_else(|| readers.keys().copied().next());
+                if let Some(evicted) = evicted {
+                    readers.remove(&evicted);
+                }
             }
-            if let None = writers.get_mut(&(index.file_number as u64)) {
-                let mut new_file = self.base_directory.clone();
-                new_file.push(format!("file_{}.new", index.file_number));
-                let new_writer = OpenOptions::new()
-                    .create(true)
-                    .read(true)
-                    .append(true)
-                    .open(&new_file)?;
-                writers.insert(index.file_number as u64, BufWriter::new(new_writer));
+            let data_file = data_file_path(&shared.base_directory, index.file_number);
+            readers.insert(index.file_number, BufReader::new(File::open(data_file)?));
+        }
+        let reader = readers
+            .get_mut(&index.file_number)
+            .expect("File not found!");
+        read_record_at(reader, &shared.base_directory, index)
+    }
+}
+
+// Methods working on the writer take it locked, so that a caller can chain them
+impl SharedStore {
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Index entry of a key - None if the key is absent or expired
+    fn lookup(&self, key: &[u8]) -> Result<Option<KvIndex>> {
+        let now = now_timestamp();
+        Ok(self
+            .index_map
+            .read()?
+            .get(key)
+            .filter(|index| !index.is_expired(now))
+            .cloned())
+    }
+
+    /// Evict the expired keys from the index map - Return how many were evicted.
+    /// Their records become garbage left to the compaction
+    fn sweep_expired(&self) -> Result<usize> {
+        let now = now_timestamp();
+        let expired_keys: Vec<Vec<u8>> = self
+            .index_map
+            .read()?
+            .values()
+            .filter(|index| index.is_expired(now))
+            .map(|index| index.key.clone())
+            .collect();
+        if expired_keys.is_empty() {
+            return Ok(0);
+        }
+        let mut writer = self.writer.lock()?;
+        let mut evicted: Vec<KvIndex> = Vec::with_capacity(expired_keys.len());
+        {
+            // Keys written again since the lookup are left alone
+            let mut index_map = self.index_map.write()?;
+            for key in expired_keys {
+                if index_map
+                    .get(&key)
+                    .is_some_and(|index| index.is_expired(now))
+                {
+                    evicted.extend(index_map.remove(&key));
+                }
             }
-            let writer = writers
-                .get_mut(&(index.file_number as u64))
-                .expect("File not found and not created...");
-            let reader = self
-                .readers
-                .get_mut(&(index.file_number as u64))
-                .expect("File not found");
-            //
-
-            reader.seek(SeekFrom::Start(index.record_offset))?;
-            reader.take(index.record_length + 8);
-            let cur_pos = writer.seek(SeekFrom::Current(0))?;
-            let copied_length = io::copy(reader, writer)?;
-            let new_index =
-                KvIndex::new(cle.clone(), index.file_number, cur_pos, index.record_length);
-            new_index_map.insert(cle.clone(), new_index);
-            debug!(
-                "COMPACTION : {} bytes copied from reader to writer",
-                copied_length
-            );
         }
+        for index in &evicted {
+            writer.record_superseded(index);
+        }
+        debug!("{} expired keys evicted", evicted.len());
+        self.maybe_compact(&mut writer)?;
+        Ok(evicted.len())
+    }
+
+    fn flush(&self, writer: &mut KvWriter) -> Result<()> {
+        for file_writer in [
+            &mut writer.active_file_writer,
+            &mut writer.index_file_writer,
+        ]
+        .iter_mut()
+        .filter_map(|file_writer| file_writer.as_mut())
+        {
+            file_writer.flush()?;
+        }
+        self.flushed_end.store(writer.active_end, Ordering::SeqCst);
+        Ok(())
+    }
 
-        // At this stage, we have now 1..N files named file_XX.new in our working directory$
-        // We shall now rename old .bdd files and rename our new .bdd files
-        let new_files: Vec<u64> = fs::read_dir(&self.base_directory)?
-            .flat_map(|x| -> Result<_> { Ok(x?.path()) })
-            .filter(|file| file.is_file() && file.extension() == Some("new".as_ref()))
-            .flat_map(|file| {
-                file.file_stem()
-                    .and_then(OsStr::to_str)
-                    .map(|name| name.trim_start_matches("file_")) // Yield an Option(String)
-                    .map(str::parse::<u64>) //Yield an Option(Option(u64) )
-            }) //Yield an Option(String) -- One level of Option has been removed by the "flat"
-            .flatten() //Extract the value
-            .collect(); //Consume the iterator
-
-        for path in new_files {
-            //Our old files are now renamed
-            fs::rename(format!("file_{}.bdd", path), format!("file_{}.old", path))?;
-            fs::rename(format!("file_{}.new", path), format!("file_{}.bdd", path))?;
-        }
-        // Replacement of the old index_map
-        // On large systems this may not be a viable option if the index_map takes gygabytes of
-        // memory - It may be wiser to just update the map
-        self.index_map = new_index_map;
+    fn sync(&self, writer: &mut KvWriter) -> Result<()> {
+        self.flush(writer)?;
+        for file_writer in [&writer.active_file_writer, &writer.index_file_writer]
+            .iter()
+            .filter_map(|file_writer| file_writer.as_ref())
+        {
+            file_writer.get_ref().sync_data()?;
+        }
+        writer.writes_since_sync = 0;
         Ok(())
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Write the serialized key/value structure to the current file.
-    /// We still need to write the partitionning mechanism
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let kvrecord: KvRecord = KvRecord::new(key.clone(), value);
-        let serial_kvrecord = serde_json::to_string(&kvrecord)?;
-        let size_of_record = serial_kvrecord.as_bytes().len();
-        //       let mut log_file = OpenOptions::new()
-        //          .create(true)
-        //         .append(true)
-        //         .open(&self.active_file_path)?;
-        let pos = self.active_file_writer.seek(SeekFrom::End(0))?;
-        let buf_sizeof = size_of_record.to_ne_bytes();
-        self.active_file_writer.write(&buf_sizeof)?;
-        self.active_file_writer.write(serial_kvrecord.as_bytes())?;
-        let index: KvIndex = KvIndex::new(
-            key.clone(),
-            self.active_file_number,
-            pos,
-            size_of_record as u64,
+    /// Sync the writes made since the last sync, if any
+    fn sync_pending(&self) -> Result<()> {
+        let mut writer = self.writer.lock()?;
+        if writer.writes_since_sync > 0 {
+            self.sync(&mut writer)?;
+        }
+        Ok(())
+    }
+
+    /// Sync after a write when the sync policy asks for it.
+    /// With an interval the background syncer does it, see spawn_syncer
+    fn sync_write(&self, writer: &mut KvWriter) -> Result<()> {
+        writer.writes_since_sync += 1;
+        let due = match self.options.sync {
+            SyncPolicy::Never | SyncPolicy::Interval(_) => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(writes) => writer.writes_since_sync >= writes,
+        };
+        if due {
+            self.sync(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Seal the active file and merge every data file in a background thread.
+    /// The generations of the merged files are reserved between the sealed files and the new
+    /// active file, so that records written meanwhile keep overriding the merged ones.
+    fn start_compaction(&self, writer: &mut KvWriter) -> Result<()> {
+        let old_files: Vec<u64> = writer.file_stats.keys().copied().collect();
+        let snapshot: Vec<KvIndex> = self.index_map.read()?.values().cloned().collect();
+        let live_bytes: u64 = snapshot
+            .iter()
+            .map(|index| RECORD_HEADER_SIZE + index.record_length)
+            .sum();
+        // Every merged file but the last one goes past max_file_size
+        let max_file_size = self.options.max_file_size;
+        let first_generation = writer.active_file_number + 1;
+        let last_generation = first_generation + live_bytes / max_file_size;
+        self.seal_active_file(writer, last_generation + 1)?;
+
+        info!(
+            "Compaction of {} data files started - {} live bytes to copy",
+            old_files.len(),
+            live_bytes
         );
-        self.index_map.insert(key.clone(), index.clone());
+        let directory = self.base_directory.clone();
+        let store = self.this.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let output = compaction::merge(
+                directory,
+                snapshot,
+                first_generation..=last_generation,
+                max_file_size,
+            );
+            // The store may be gone already, nothing to report then
+            if sender.send(output).is_err() {
+                return;
+            }
+            // Install it now rather than on the next write, which may never come
+            if let Some(shared) = store.upgrade() {
+                if let Ok(mut writer) = shared.writer.lock() {
+                    shared.poll_compaction(&mut writer);
+                }
+            }
+        });
+        writer.compaction = Some(CompactionTask {
+            receiver,
+            old_files,
+            first_generation,
+            last_generation,
+        });
+        Ok(())
+    }
 
-        // Insertion in the index_file is performed as soon as the record has been set
-        // This ensure that no data is lost
-        let serialis = serde_json::to_string(&index)?;
-        let size_of = serialis.len().to_ne_bytes();
-        self.index_file_writer.write(&size_of)?;
-        self.index_file_writer.write(serialis.as_bytes())?;
+    /// Install the compaction if the background thread is done - Never blocks.
+    /// Writes poll it and so does the background thread once merged.
+    /// A compaction that failed is only logged : The old files are still in charge and the
+    /// write that polled it goes ahead
+    fn poll_compaction(&self, writer: &mut KvWriter) {
+        let output = match &writer.compaction {
+            Some(task) => match task.receiver.try_recv() {
+                Ok(output) => Some(output),
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => None,
+            },
+            None => return,
+        };
+        if let Some(task) = writer.compaction.take() {
+            if let Err(x) = self.install_compaction(writer, task, output) {
+                error!("Background compaction dropped : {:?}", x);
+            }
+        }
+    }
 
-        //We shoud check here if it is not time to create a new file
-        if pos as u64 + size_of_record as u64 > MAX_SIZE_THRESHOLD {
-            self.active_file_writer.flush()?;
-            self.active_file_number += 1;
-            let mut new_activefile = self.base_directory.clone();
-            new_activefile.push(format!("file_{}.bdd", self.active_file_number));
-            self.active_file_writer = BufWriter::new(
-                OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .write(true)
-                    .open(&new_activefile)?,
+    /// Wait for a compaction and install it
+    fn finish_compaction(&self, writer: &mut KvWriter, task: CompactionTask) -> Result<()> {
+        let output = task.receiver.recv().ok();
+        self.install_compaction(writer, task, output)
+    }
+
+    /// Index entries still pointing at the records that were copied now point at their copy,
+    /// entries that changed meanwhile are left alone. Then the manifest is replaced to mark
+    /// older generations obsolete, and only then are the old files deleted.
+    /// A get that looked its key up before the switch may find its file deleted, it looks
+    /// the key up again then.
+    fn install_compaction(
+        &self,
+        writer: &mut KvWriter,
+        task: CompactionTask,
+        output: Option<Result<compaction::CompactionOutput>>,
+    ) -> Result<()> {
+        let output = match output {
+            Some(Ok(output)) => output,
+            Some(Err(x)) => {
+                error!("Compaction failed : {:?}", x);
+                self.discard_compaction(&task);
+                return Err(x);
+            }
+            None => {
+                error!("Compaction thread stopped without a result");
+                self.discard_compaction(&task);
+                return Ok(());
+            }
+        };
+
+        let mut merged_bytes: u64 = 0;
+        for file_number in &output.merged_files {
+            merged_bytes += fs::metadata(data_file_path(&self.base_directory, *file_number))?.len();
+            writer.file_stats.insert(*file_number, FileStats::default());
+        }
+        {
+            let mut index_map = self.index_map.write()?;
+            for (old_index, new_index) in output.moved {
+                if let Some(index) = index_map.get_mut(&old_index.key) {
+                    if index.file_number == old_index.file_number
+                        && index.record_offset == old_index.record_offset
+                    {
+                        *index = new_index;
+                    }
+                }
+            }
+            // Expired records were not copied, their entries would point at deleted files
+            for old_index in output.dropped {
+                let unchanged = index_map.get(&old_index.key).is_some_and(|index| {
+                    index.file_number == old_index.file_number
+                        && index.record_offset == old_index.record_offset
+                });
+                if unchanged {
+                    index_map.remove(&old_index.key);
+                }
+            }
+        }
+
+        // Commit point : From now on the old files are obsolete
+        write_manifest(
+            &self.base_directory,
+            &KvManifest {
+                min_generation: task.first_generation,
+            },
+        )?;
+        self.min_generation
+            .store(task.first_generation, Ordering::SeqCst);
+
+        let mut old_bytes: u64 = 0;
+        for file_number in task.old_files {
+            writer.file_stats.remove(&file_number);
+            old_bytes += fs::metadata(data_file_path(&self.base_directory, file_number))?.len();
+            fs::remove_file(data_file_path(&self.base_directory, file_number))?;
+            let _ = fs::remove_file(hint_file_path(&self.base_directory, file_number));
+        }
+        self.compute_stats(writer)?;
+        info!(
+            "Compaction done - {} bytes reclaimed",
+            old_bytes.saturating_sub(merged_bytes)
+        );
+        Ok(())
+    }
+
+    /// Remove whatever a failed compaction wrote - The store never loaded any of it
+    fn discard_compaction(&self, task: &CompactionTask) {
+        for file_number in task.first_generation..=task.last_generation {
+            for file in [
+                compaction::compaction_file_path(&self.base_directory, file_number),
+                data_file_path(&self.base_directory, file_number),
+                hint_file_path(&self.base_directory, file_number),
+            ] {
+                if let Err(x) = fs::remove_file(&file) {
+                    if x.kind() != io::ErrorKind::NotFound {
+                        error!("Could not remove {} : {:?}", file.display(), x);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Count live and dead bytes of every data file from the index map
+    fn compute_stats(&self, writer: &mut KvWriter) -> Result<()> {
+        let mut file_stats: HashMap<u64, FileStats> = HashMap::new();
+        for file_number in writer.file_stats.keys() {
+            let data_file = data_file_path(&self.base_directory, *file_number);
+            file_stats.insert(
+                *file_number,
+                FileStats {
+                    live_bytes: 0,
+                    dead_bytes: fs::metadata(data_file)?
+                        .len()
+                        .saturating_sub(FILE_HEADER_SIZE),
+                },
             );
-            new_activefile.pop();
-            new_activefile.push(format!("file_{}.bdd", self.active_file_number - 1));
-            self.readers.insert(
-                (self.active_file_number - 1) as u64,
-                BufReader::new(File::open(&new_activefile)?),
+        }
+        for index in self.index_map.read()?.values() {
+            let size = RECORD_HEADER_SIZE + index.record_length;
+            let stats = file_stats.entry(index.file_number).or_default();
+            stats.live_bytes += size;
+            stats.dead_bytes = stats.dead_bytes.saturating_sub(size);
+        }
+        writer.file_stats = file_stats;
+        Ok(())
+    }
+
+    /// Start a background compaction when the policy says there is enough garbage
+    fn maybe_compact(&self, writer: &mut KvWriter) -> Result<()> {
+        if writer.compaction.is_some() || self.options.read_only {
+            return Ok(());
+        }
+        let (live_bytes, dead_bytes) = writer
+            .file_stats
+            .values()
+            .fold((0, 0), |(live, dead), stats| {
+                (live + stats.live_bytes, dead + stats.dead_bytes)
+            });
+        if writer
+            .compaction_policy
+            .triggers(live_bytes, dead_bytes, self.options.max_file_size)
+        {
+            debug!(
+                "{} dead bytes for {} live bytes - Starting a compaction",
+                dead_bytes, live_bytes
             );
+            self.start_compaction(writer)?;
         }
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if key == "".to_string() {
-            debug!("get Function - No key was provided.");
-            Ok(Some("Key not found".to_string()))
+    /// Create a new active file once the current one went past max_file_size
+    fn roll_active_file(&self, writer: &mut KvWriter) -> Result<()> {
+        if writer.active_end <= self.options.max_file_size {
+            return Ok(());
+        }
+        let next_file_number = writer.active_file_number + 1;
+        self.seal_active_file(writer, next_file_number)
+    }
+
+    /// Seal the active file and start the given one.
+    /// The sealed file gets its hint file, written from the entries kept for the index file,
+    /// and the index file starts over for the new active file
+    fn seal_active_file(&self, writer: &mut KvWriter, next_file_number: u64) -> Result<()> {
+        // Whatever the policy, a sealed file is complete on disk unless syncs are disabled
+        if self.options.sync == SyncPolicy::Never {
+            self.flush(writer)?;
         } else {
-            let index = self.index_map.get(&key);
-            match index {
-                Some(idx) => {
-                    debug!(
-                        "And index has been found. Record offset is {}",
-                        idx.record_offset
-                    );
-                    let reader = self
-                        .readers
-                        .get_mut(&(idx.file_number as u64))
-                        .expect("File not found!");
-
-                    let mut buf_size_of = [0u8; 8];
-                    debug!("Seeking in the file");
-                    reader.seek(SeekFrom::Start(idx.record_offset))?;
-                    debug!("Reading bytes");
-                    reader.take(8).read(&mut buf_size_of)?;
-
-                    let record_size = i64::from_ne_bytes(buf_size_of);
-                    if record_size > 0 {
-                        debug!("Record size is {}", record_size);
-                        let mut read_vector = vec![];
-                        reader
-                            .take(record_size as u64)
-                            .read_to_end(&mut read_vector)?;
-                        let record: KvRecord = serde_json::from_slice(read_vector.as_slice())?;
-                        return Ok(Some(record.value));
+            self.sync(writer)?;
+        }
+        write_hint_file(
+            &self.base_directory,
+            writer.active_file_number,
+            &writer.active_entries,
+        )?;
+        writer.active_entries.clear();
+
+        let new_activefile = data_file_path(&self.base_directory, next_file_number);
+        let (active_file_writer, active_end) = open_data_file(&new_activefile)?;
+        writer.active_file_writer = Some(active_file_writer);
+        writer.active_file_number = next_file_number;
+        writer.active_end = active_end;
+        writer
+            .file_stats
+            .insert(next_file_number, FileStats::default());
+        writer.index_file_writer = None;
+        writer.index_file_writer = Some(write_index_file(
+            &self.base_directory,
+            &BTreeMap::new(),
+            None,
+        )?);
+        // Readers of the sealed file find it complete whatever they see here
+        self.flushed_end.store(0, Ordering::SeqCst);
+        self.active_file_number
+            .store(next_file_number, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Write records between a begin and a commit marker
+    fn write_batch(&self, writer: &mut KvWriter, records: Vec<KvRecord>) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut framed_records = Vec::with_capacity(records.len() + 2);
+        framed_records.push(KvRecord::marker(BatchMarker::Begin));
+        framed_records.extend(records);
+        framed_records.push(KvRecord::marker(BatchMarker::Commit));
+        self.write(writer, &framed_records)
+    }
+
+    /// Append a batch of records and their index entries, then update the index map.
+    /// Records are written in one contiguous append and the index map is updated under a
+    /// single lock : Readers see the whole batch or nothing of it
+    fn write(&self, writer: &mut KvWriter, records: &[KvRecord]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.poll_compaction(writer);
+        let indexes = writer.append_records(records)?;
+        self.sync_write(writer)?;
+
+        let old_indexes: Vec<Option<KvIndex>> = {
+            let mut index_map = self.index_map.write()?;
+            indexes
+                .iter()
+                .map(|index| {
+                    if index.marker.is_some() {
+                        None
+                    } else if index.tombstone {
+                        index_map.remove(&index.key)
                     } else {
-                        debug!("Record size is < 0 ");
-                        return Ok(Some("Key not found".to_string()));
+                        index_map.insert(index.key.clone(), index.clone())
                     }
-                }
+                })
+                .collect()
+        };
+        for (index, old_index) in indexes.iter().zip(old_indexes) {
+            // Tombstones and markers are garbage as soon as the files before them are compacted
+            writer.record_written(
+                index.record_length,
+                !index.tombstone && index.marker.is_none(),
+            );
+            if let Some(old_index) = &old_index {
+                writer.record_superseded(old_index);
+            }
+        }
+
+        //We shoud check here if it is not time to create a new file
+        self.roll_active_file(writer)?;
+        self.maybe_compact(writer)?;
+        Ok(())
+    }
+}
+
+impl KvWriter {
+    /// Append records at the end of the active file, then their entries to the index file.
+    /// Both are serialized first and written at once. If anything fails the active file is
+    /// cut back to where it was, a stale index file is caught when the store is opened again.
+    /// Return the index entries of the records
+    fn append_records(&mut self, records: &[KvRecord]) -> Result<Vec<KvIndex>> {
+        let file_number = self.active_file_number;
+        let pos = self.active_end;
+        let mut record_bytes: Vec<u8> = Vec::new();
+        let mut indexes: Vec<KvIndex> = Vec::with_capacity(records.len());
+        for record in records {
+            let record_offset = pos + record_bytes.len() as u64;
+            let size_of_record = write_record(&mut record_bytes, record)?;
+            indexes.push(KvIndex::from_record(
+                record,
+                file_number,
+                record_offset,
+                size_of_record,
+            ));
+        }
+        let mut index_bytes: Vec<u8> = Vec::new();
+        for index in &indexes {
+            write_entry(&mut index_bytes, index)?;
+        }
+
+        let file_writer = self.active_file_writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        if let Err(x) = file_writer.write_all(&record_bytes) {
+            self.truncate_active_file(pos);
+            return Err(KvsError::Io(x));
+        }
+        self.active_end = pos + record_bytes.len() as u64;
+        // Insertion in the index_file is performed as soon as the records have been written
+        // This ensure that no data is lost
+        let index_writer = self.index_file_writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        if let Err(x) = index_writer.write_all(&index_bytes) {
+            self.truncate_active_file(pos);
+            return Err(KvsError::Io(x));
+        }
+        for index in indexes.iter().filter(|index| index.marker.is_none()) {
+            self.active_entries.insert(index.key.clone(), index.clone());
+        }
+        Ok(indexes)
+    }
+
+    // Drop whatever was appended to the active file past the given offset
+    fn truncate_active_file(&mut self, end: u64) {
+        if let Some(file_writer) = self.active_file_writer.as_mut() {
+            let _ = file_writer.flush();
+            if let Err(x) = file_writer.get_ref().set_len(end) {
+                error!(
+                    "Could not truncate file_{}.bdd : {:?}",
+                    self.active_file_number, x
+                );
+            }
+        }
+        self.active_end = end;
+    }
+
+    // A record was written to the active file
+    fn record_written(&mut self, size_of_record: u64, live: bool) {
+        let stats = self.file_stats.entry(self.active_file_number).or_default();
+        if live {
+            stats.live_bytes += RECORD_HEADER_SIZE + size_of_record;
+        } else {
+            stats.dead_bytes += RECORD_HEADER_SIZE + size_of_record;
+        }
+    }
+
+    // A record was overwritten or removed
+    fn record_superseded(&mut self, index: &KvIndex) {
+        let size = RECORD_HEADER_SIZE + index.record_length;
+        let stats = self.file_stats.entry(index.file_number).or_default();
+        stats.live_bytes = stats.live_bytes.saturating_sub(size);
+        stats.dead_bytes += size;
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Write the serialized key/value structure to the current file.
+    /// A new file is started when the current one is too big
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        shared.write(&mut writer, &[KvRecord::new(key, value)])
+    }
+
+    /// Read the value of a key.
+    /// Ok(None) is returned when the key is empty, not indexed or when its record was removed
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        // A compaction may delete the data file between the lookup and the read
+        let mut attempts = 0;
+        loop {
+            let index = match self.shared.lookup(&key)? {
+                Some(idx) => idx,
                 None => {
                     debug!("No index record was found.");
-                    debug!("Map is {:?}", self.index_map);
-                    Ok(Some("Key not found".to_string()))
+                    return Ok(None);
                 }
+            };
+            debug!(
+                "And index has been found. Record offset is {}",
+                index.record_offset
+            );
+            match self.read_record(&index) {
+                Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    debug!(
+                        "file_{}.bdd is gone - Looking the key up again",
+                        index.file_number
+                    );
+                    attempts += 1;
+                }
+                record => return Ok(record?.value),
             }
         }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if key == "".to_string() {
-            return Ok(());
-        } else {
-            let index = self.index_map.get(&key);
-            match index {
-                Some(idx) => {
-                    let mut log_file = self.base_directory.clone();
-                    log_file.push(format!("file_{}", idx.file_number).as_str());
-                    let mut log_file = OpenOptions::new().read(true).write(true).open(&log_file)?;
-                    let mut buf_size_of = [0u8; 8];
-                    std::io::Read::by_ref(&mut log_file)
-                        .seek(SeekFrom::Start(idx.record_offset))?;
-                    std::io::Read::by_ref(&mut log_file)
-                        .take(8)
-                        .read(&mut buf_size_of)?;
-                    let mut record_size = i64::from_ne_bytes(buf_size_of);
-                    if record_size > 0 {
-                        record_size = record_size * -1;
+    /// Remove a key by appending a tombstone to the active file.
+    /// The tombstone is indexed like any other write so that the remove survives a restart
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        if shared.lookup(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        shared.write(&mut writer, &[KvRecord::tombstone(key)])
+    }
+
+    /// The expiry is written along with the value, it survives a restart
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        shared.write(
+            &mut writer,
+            &[KvRecord::expiring(
+                key.into_bytes(),
+                value.into_bytes(),
+                ttl,
+            )],
+        )
+    }
+
+    /// The value is read back and written again with its new expiry
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let mut writer = shared.writer.lock()?;
+        let index = shared
+            .lookup(key.as_bytes())?
+            .ok_or(KvsError::KeyNotFound)?;
+        // The record may still be buffered and read_record would lock the writer to flush it
+        shared.flush(&mut writer)?;
+        let value = self
+            .read_record(&index)?
+            .value
+            .ok_or(KvsError::KeyNotFound)?;
+        shared.write(
+            &mut writer,
+            &[KvRecord::expiring(key.into_bytes(), value, ttl)],
+        )
+    }
+
+    /// Answered from the index map, data files are not read
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let index = self
+            .shared
+            .lookup(key.as_bytes())?
+            .ok_or(KvsError::KeyNotFound)?;
+        let now = now_timestamp();
+        Ok(index
+            .expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+    }
+
+    /// Looked up in the index map, data files are not read
+    fn exists(&self, key: String) -> Result<bool> {
+        Ok(self.shared.lookup(key.as_bytes())?.is_some())
+    }
+
+    /// Write every pair in one batch - A key set twice keeps its last value
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        self.write_batch(batch)
+    }
+
+    /// Read every key under a single lookup of the index map
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let now = now_timestamp();
+        let indexes: Vec<Option<KvIndex>> = {
+            let index_map = self.shared.index_map.read()?;
+            keys.iter()
+                .map(|key| {
+                    index_map
+                        .get(key.as_bytes())
+                        .filter(|index| !index.is_expired(now))
+                        .cloned()
+                })
+                .collect()
+        };
+        keys.into_iter()
+            .zip(indexes)
+            .map(|(key, index)| match index {
+                Some(index) => match self.read_record(&index) {
+                    // Compacted meanwhile - The slow path looks the key up again
+                    Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                        self.get(key)
                     }
-                    buf_size_of = record_size.to_ne_bytes();
-                    std::io::Write::by_ref(&mut log_file)
-                        .seek(SeekFrom::Start(idx.record_offset))?;
-                    std::io::Write::by_ref(&mut log_file).write(&buf_size_of)?;
-                    return Ok(());
-                }
-                None => return Ok(()),
+                    record => match record?.value {
+                        Some(value) => Ok(Some(String::from_utf8(value)?)),
+                        None => Ok(None),
+                    },
+                },
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Remove every key in one batch.
+    /// Nothing is removed unless all the keys are in the store
+    fn remove_many(&self, keys: Vec<String>) -> Result<()> {
+        let shared = &self.shared;
+        shared.check_writable()?;
+        let keys: BTreeSet<Vec<u8>> = keys.into_iter().map(String::into_bytes).collect();
+        let mut writer = shared.writer.lock()?;
+        {
+            let now = now_timestamp();
+            let index_map = shared.index_map.read()?;
+            let present = |key: &Vec<u8>| {
+                index_map
+                    .get(key)
+                    .is_some_and(|index| !index.is_expired(now))
+            };
+            if !keys.iter().all(present) {
+                return Err(KvsError::KeyNotFound);
             }
         }
+        let records: Vec<KvRecord> = keys.into_iter().map(KvRecord::tombstone).collect();
+        shared.write_batch(&mut writer, records)
     }
 }
@@ -0,0 +1,47 @@
+//! Write batches : Sets and removes applied to the store all at once.
+//! In the data file the records of a batch sit between a begin and a commit marker. When the
+//! store is opened, a batch missing its commit marker is discarded : A crash in the middle of
+//! a batch leaves the store as it was before the batch.
+use super::*;
+
+/// Sets and removes applied atomically by KvStore::write_batch
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(super) records: Vec<KvRecord>,
+}
+
+impl WriteBatch {
+    /// Empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set the value of a key - Later operations of the batch on the same key win.
+    /// Keys and values are bytes, strings are taken as their UTF-8 bytes
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) {
+        self.records.push(KvRecord::new(key.into(), value.into()));
+    }
+
+    /// Remove a key - Unlike KvStore::remove, a key not in the store is not an error
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) {
+        self.records.push(KvRecord::tombstone(key.into()));
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// True if the batch holds no operation
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Operations of the batch for the other engines : A value of None removes the key
+    pub(crate) fn into_operations(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.records
+            .into_iter()
+            .map(|record| (record.key, record.value))
+            .collect()
+    }
+}
@@ -0,0 +1,114 @@
+//! Merge of the data files, run in a background thread by the store.
+//! Live records are copied to file_XX.new files numbered after the sealed files, they are
+//! synced and renamed to file_XX.bdd along with their hint file. The store then replaces the
+//! manifest to mark older generations obsolete, and only then deletes the old files.
+//! A crash before the manifest is written leaves the old files in charge : Merged files only
+//! hold copies of records that were live, and every later write sits in a newer generation.
+//! Records expired by the time they are reached are not copied.
+use super::*;
+use std::ops::RangeInclusive;
+
+/// What the store needs to install a compaction
+pub(super) struct CompactionOutput {
+    // Index entries of the snapshot along with the entry of their copy
+    pub(super) moved: Vec<(KvIndex, KvIndex)>,
+    // Index entries of the snapshot that expired and were left behind
+    pub(super) dropped: Vec<KvIndex>,
+    pub(super) merged_files: Vec<u64>,
+}
+
+pub(super) fn compaction_file_path(directory: &Path, file_number: u64) -> PathBuf {
+    directory.join(format!("file_{}.new", file_number))
+}
+
+/// Create a compaction file with the header of a data file
+fn create_compaction_file(directory: &Path, file_number: u64) -> Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(compaction_file_path(directory, file_number))?);
+    write_file_header(&mut writer, DATA_FILE_MAGIC)?;
+    Ok(writer)
+}
+
+/// Copy every record of the snapshot to the given generations.
+/// The worker opens its own readers so that the store keeps serving requests meanwhile.
+pub(super) fn merge(
+    directory: PathBuf,
+    snapshot: Vec<KvIndex>,
+    generations: RangeInclusive<u64>,
+    max_file_size: u64,
+) -> Result<CompactionOutput> {
+    let (first_generation, last_generation) = generations.into_inner();
+    let mut readers: HashMap<u64, BufReader<File>> = HashMap::new();
+    let mut generation = first_generation;
+    let mut writer = create_compaction_file(&directory, generation)?;
+    let mut pos: u64 = FILE_HEADER_SIZE;
+    let mut merged_entries: BTreeMap<Vec<u8>, KvIndex> = BTreeMap::new();
+    let mut output = CompactionOutput {
+        moved: Vec::with_capacity(snapshot.len()),
+        dropped: Vec::new(),
+        merged_files: Vec::new(),
+    };
+
+    let now = now_timestamp();
+    for index in snapshot {
+        if index.is_expired(now) {
+            output.dropped.push(index);
+            continue;
+        }
+        let reader = match readers.get_mut(&index.file_number) {
+            Some(reader) => reader,
+            None => {
+                let data_file = data_file_path(&directory, index.file_number);
+                readers
+                    .entry(index.file_number)
+                    .or_insert(BufReader::new(File::open(data_file)?))
+            }
+        };
+        // Records are read back through their checksum, corruption is not copied silently
+        let record = read_record_at(reader, &directory, &index)?;
+        let record_length = write_record(&mut writer, &record)?;
+        let new_index = KvIndex::from_record(&record, generation, pos, record_length);
+        pos = new_index.record_end();
+        merged_entries.insert(index.key.clone(), new_index.clone());
+        output.moved.push((index, new_index));
+        // The last reserved generation takes whatever is left
+        if pos > max_file_size && generation < last_generation {
+            finish_compaction_file(&directory, writer, generation, &merged_entries)?;
+            output.merged_files.push(generation);
+            merged_entries.clear();
+            generation += 1;
+            pos = FILE_HEADER_SIZE;
+            writer = create_compaction_file(&directory, generation)?;
+        }
+    }
+    if pos > FILE_HEADER_SIZE {
+        finish_compaction_file(&directory, writer, generation, &merged_entries)?;
+        output.merged_files.push(generation);
+    } else {
+        drop(writer);
+        fs::remove_file(compaction_file_path(&directory, generation))?;
+    }
+    debug!(
+        "COMPACTION : {} records copied to {} files from generation {} - {} expired records dropped",
+        output.moved.len(),
+        output.merged_files.len(),
+        first_generation,
+        output.dropped.len()
+    );
+    Ok(output)
+}
+
+/// Make a compacted file durable and give it its final name and its hint file
+fn finish_compaction_file(
+    directory: &Path,
+    mut writer: BufWriter<File>,
+    file_number: u64,
+    entries: &BTreeMap<Vec<u8>, KvIndex>,
+) -> Result<()> {
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(
+        compaction_file_path(directory, file_number),
+        data_file_path(directory, file_number),
+    )?;
+    write_hint_file(directory, file_number, entries)
+}
@@ -0,0 +1,302 @@
+//! Conversion of the data files written by older versions.
+//! The first versions stored JSON records laid out as |Sizeofrecord(8 bytes)|JSON|, a removed
+//! record having its size negated in place. A CRC32 was added next :
+//! |Sizeofrecord(8 bytes)|CRC32(4 bytes)|JSON|.
+//! The next ones stored binary records without a file header, their integers in the byte order
+//! of the machine. Hint and index files point at the old offsets, so they are removed before any
+//! data file is converted. Each data file is copied in the current format to a .tmp file, which
+//! is synced and renamed over the old one : An interrupted migration leaves every file in one
+//! format or the other, and converted files are skipped when it is run again.
+use super::*;
+
+// Header of a JSON record of the first versions : Its length, negated once removed
+const LEGACY_JSON_HEADER_SIZE: u64 = 8;
+
+// Header of a JSON record : Its length and the CRC32 of the serialized record
+const JSON_HEADER_SIZE: u64 = 12;
+
+// Offset and size of the integers of a record header, the byte order of the machine aside
+const RECORD_HEADER_FIELDS: [(usize, usize); 6] =
+    [(0, 2), (4, 4), (8, 4), (12, 8), (20, 8), (28, 4)];
+
+/// Format a data file is written in
+pub(super) enum FileFormat {
+    /// Data file header with the current version
+    Current,
+    /// Too short to hold a data file header
+    Empty,
+    /// JSON records without checksum
+    LegacyJson,
+    /// JSON records
+    Json,
+    /// Binary records without a data file header
+    Unversioned,
+    /// Data file header with another version
+    Version(u32),
+}
+
+// A record as serialized by the versions storing JSON records
+#[derive(Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: Option<String>,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    marker: Option<BatchMarker>,
+}
+
+impl From<JsonRecord> for KvRecord {
+    fn from(record: JsonRecord) -> KvRecord {
+        KvRecord {
+            key: record.key.into_bytes(),
+            value: record.value.map(String::into_bytes),
+            timestamp: record.timestamp,
+            expires_at: record.expires_at,
+            marker: record.marker,
+        }
+    }
+}
+
+// What was found reading one record of an old data file
+enum OldRecord {
+    // The record and its length, header included
+    Valid(KvRecord, u64),
+    // The file ends before the record does
+    Incomplete,
+    // The record does not match its checksum - Its length, header included
+    Damaged(u64),
+}
+
+fn read_json_header<R: Read>(reader: &mut R) -> Result<(u64, u32)> {
+    let mut buf_size_of = [0u8; 8];
+    let mut buf_checksum = [0u8; 4];
+    reader.read_exact(&mut buf_size_of)?;
+    reader.read_exact(&mut buf_checksum)?;
+    Ok((
+        u64::from_ne_bytes(buf_size_of),
+        u32::from_ne_bytes(buf_checksum),
+    ))
+}
+
+/// Read a JSON record - Left is what remains of the file
+fn read_json_record<R: Read>(reader: &mut R, left: u64) -> Result<OldRecord> {
+    let (record_length, checksum) = read_json_header(reader)?;
+    if record_length > left - JSON_HEADER_SIZE {
+        return Ok(OldRecord::Incomplete);
+    }
+    let mut record_bytes = vec![0u8; record_length as usize];
+    reader.read_exact(&mut record_bytes)?;
+    if crc32fast::hash(&record_bytes) != checksum {
+        return Ok(OldRecord::Damaged(JSON_HEADER_SIZE + record_length));
+    }
+    let record: JsonRecord = serde_json::from_slice(record_bytes.as_slice())?;
+    Ok(OldRecord::Valid(
+        record.into(),
+        JSON_HEADER_SIZE + record_length,
+    ))
+}
+
+/// Read a JSON record of the first versions - Left is what remains of the file.
+/// A record removed in place becomes a tombstone for its key
+fn read_legacy_json_record<R: Read>(reader: &mut R, left: u64) -> Result<OldRecord> {
+    let mut buf_size_of = [0u8; 8];
+    reader.read_exact(&mut buf_size_of)?;
+    let size_of_record = i64::from_ne_bytes(buf_size_of);
+    let record_length = size_of_record.unsigned_abs();
+    if record_length > left - LEGACY_JSON_HEADER_SIZE {
+        return Ok(OldRecord::Incomplete);
+    }
+    let mut record_bytes = vec![0u8; record_length as usize];
+    reader.read_exact(&mut record_bytes)?;
+    let mut record: KvRecord = match serde_json::from_slice::<JsonRecord>(&record_bytes) {
+        Ok(record) => record.into(),
+        Err(_) => return Ok(OldRecord::Damaged(LEGACY_JSON_HEADER_SIZE + record_length)),
+    };
+    if size_of_record < 0 {
+        record.value = None;
+    }
+    Ok(OldRecord::Valid(
+        record,
+        LEGACY_JSON_HEADER_SIZE + record_length,
+    ))
+}
+
+/// Read a binary record of a file without header - Left is what remains of the file.
+/// A header that cannot be read damages the rest of the file
+fn read_unversioned_record<R: Read>(reader: &mut R, left: u64) -> Result<OldRecord> {
+    let mut bytes = [0u8; RECORD_HEADER_SIZE as usize];
+    reader.read_exact(&mut bytes)?;
+    if u16::from_ne_bytes([bytes[0], bytes[1]]) != RECORD_MAGIC || bytes[2] != RECORD_VERSION {
+        return Ok(OldRecord::Damaged(left));
+    }
+    let key_length = u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as u64;
+    let value_length = u32::from_ne_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as u64;
+    let record_length = key_length + value_length;
+    if record_length > left - RECORD_HEADER_SIZE {
+        return Ok(OldRecord::Incomplete);
+    }
+    let mut record_bytes = vec![0u8; record_length as usize];
+    reader.read_exact(&mut record_bytes)?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&bytes[..28]);
+    hasher.update(&record_bytes);
+    if hasher.finalize() != u32::from_ne_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]) {
+        return Ok(OldRecord::Damaged(RECORD_HEADER_SIZE + record_length));
+    }
+    if cfg!(target_endian = "big") {
+        for (offset, size) in RECORD_HEADER_FIELDS {
+            bytes[offset..offset + size].reverse();
+        }
+    }
+    let record = RecordHeader { bytes }.decode(record_bytes)?;
+    Ok(OldRecord::Valid(record, RECORD_HEADER_SIZE + record_length))
+}
+
+/// Tell which format a data file is written in
+pub(super) fn file_format(data_file: &Path) -> Result<FileFormat> {
+    let mut reader = BufReader::new(File::open(data_file)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    if file_len < FILE_HEADER_SIZE {
+        return Ok(FileFormat::Empty);
+    }
+    match read_file_header(&mut reader, DATA_FILE_MAGIC)? {
+        Some(FORMAT_VERSION) => return Ok(FileFormat::Current),
+        Some(version) => return Ok(FileFormat::Version(version)),
+        None => (),
+    }
+    // The first record tells the old formats apart
+    reader.seek(SeekFrom::Start(0))?;
+    if file_len >= JSON_HEADER_SIZE {
+        if let OldRecord::Valid(..) = read_json_record(&mut reader, file_len)? {
+            return Ok(FileFormat::Json);
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        if let OldRecord::Valid(..) = read_legacy_json_record(&mut reader, file_len)? {
+            return Ok(FileFormat::LegacyJson);
+        }
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    if file_len >= RECORD_HEADER_SIZE {
+        if let OldRecord::Valid(..) | OldRecord::Incomplete =
+            read_unversioned_record(&mut reader, file_len)?
+        {
+            return Ok(FileFormat::Unversioned);
+        }
+    }
+    error!("Unknown format for {}", data_file.display());
+    Err(KvsError::Corrupted {
+        file: data_file.to_path_buf(),
+        offset: 0,
+    })
+}
+
+/// Convert every data file written by an older version - Return the number of records converted
+pub(super) fn migrate_directory(directory: &Path) -> Result<u64> {
+    let min_generation = read_manifest(directory)?.min_generation;
+    let data_files: Vec<u64> = search_bdd_files(directory)?
+        .into_iter()
+        .filter(|file_number| *file_number >= min_generation)
+        .collect();
+    let active_file_number = data_files.iter().copied().max();
+    let mut old_files: Vec<(u64, FileFormat)> = Vec::new();
+    for file_number in data_files {
+        let data_file = data_file_path(directory, file_number);
+        match file_format(&data_file)? {
+            FileFormat::Current | FileFormat::Empty => (),
+            FileFormat::Version(version) => {
+                return Err(KvsError::UnsupportedVersion {
+                    file: data_file,
+                    version,
+                })
+            }
+            format => old_files.push((file_number, format)),
+        }
+    }
+    if old_files.is_empty() {
+        info!("No data file to migrate in {}", directory.display());
+        return Ok(0);
+    }
+
+    for (file_number, _) in &old_files {
+        let hint_file = hint_file_path(directory, *file_number);
+        if hint_file.exists() {
+            fs::remove_file(hint_file)?;
+        }
+    }
+    let index_file = directory.join(INDEX_FILE);
+    if index_file.exists() {
+        fs::remove_file(index_file)?;
+    }
+
+    let mut migrated = 0;
+    for (file_number, format) in old_files {
+        let is_active = Some(file_number) == active_file_number;
+        migrated += migrate_file(directory, file_number, format, is_active)?;
+    }
+    Ok(migrated)
+}
+
+/// Copy the records of an old data file in the current format and put the copy in its place.
+/// A torn record at the end of the active file is dropped, as the store would do on open
+fn migrate_file(
+    directory: &Path,
+    file_number: u64,
+    format: FileFormat,
+    is_active: bool,
+) -> Result<u64> {
+    let data_file = data_file_path(directory, file_number);
+    let tmp_file = data_file.with_extension("bdd.tmp");
+    let mut reader = BufReader::new(File::open(&data_file)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    let mut writer = BufWriter::new(File::create(&tmp_file)?);
+    write_file_header(&mut writer, DATA_FILE_MAGIC)?;
+    let header_size = match format {
+        FileFormat::Json => JSON_HEADER_SIZE,
+        FileFormat::LegacyJson => LEGACY_JSON_HEADER_SIZE,
+        _ => RECORD_HEADER_SIZE,
+    };
+    let mut pos: u64 = 0;
+    let mut migrated: u64 = 0;
+    while pos + header_size <= file_len {
+        let old_record = match format {
+            FileFormat::Json => read_json_record(&mut reader, file_len - pos)?,
+            FileFormat::LegacyJson => read_legacy_json_record(&mut reader, file_len - pos)?,
+            _ => read_unversioned_record(&mut reader, file_len - pos)?,
+        };
+        match old_record {
+            OldRecord::Valid(record, length) => {
+                write_record(&mut writer, &record)?;
+                migrated += 1;
+                pos += length;
+            }
+            OldRecord::Incomplete => {
+                warn!(
+                    "Incomplete record at offset {} of file_{}.bdd",
+                    pos, file_number
+                );
+                break;
+            }
+            OldRecord::Damaged(length) => {
+                if is_active && pos + length == file_len {
+                    warn!("Torn record at offset {} of file_{}.bdd", pos, file_number);
+                    break;
+                }
+                return Err(KvsError::Corrupted {
+                    file: data_file,
+                    offset: pos,
+                });
+            }
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_file, &data_file)?;
+    info!(
+        "file_{}.bdd migrated to format version {} : {} records",
+        file_number, FORMAT_VERSION, migrated
+    );
+    Ok(migrated)
+}
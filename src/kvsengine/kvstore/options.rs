@@ -0,0 +1,200 @@
+//! Settings of a KvStore, given to KvStoreOptions::open or read from a TOML file :
+//!
+//! ```toml
+//! max_file_size = 67108864
+//! sync = { every_n = 100 }
+//! read_only = false
+//! create_dirs = true
+//! max_readers_per_handle = 64
+//! sweep_interval = 1000
+//!
+//! [compaction]
+//! garbage_ratio = 0.5
+//! dead_bytes = 1073741824
+//! ```
+//! Every setting is optional, missing ones keep their default value.
+use super::*;
+
+// Data files are sealed once they go past this size
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Each handle keeps readers open for that many data files at most
+const DEFAULT_MAX_READERS_PER_HANDLE: usize = 64;
+
+// Milliseconds between two evictions of the expired keys
+const DEFAULT_SWEEP_INTERVAL: u64 = 1000;
+
+/// When the writes to the data and index files are forced to disk.
+/// In TOML : sync = "never", "every_write", { every_n = 100 } or { interval = 1000 }
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// Leave it to the operating system - Writes are buffered by the store
+    Never,
+    /// Sync after every set and remove
+    EveryWrite,
+    /// Sync once every N sets and removes
+    EveryN(u64),
+    /// Sync every that many milliseconds while writes are pending, from a background thread
+    Interval(u64),
+}
+
+/// When a compaction is started automatically.
+/// Any threshold set to None is ignored
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompactionPolicy {
+    /// Share of dead bytes in the data files that triggers a compaction.
+    /// It only applies once a data file worth of bytes is dead
+    pub garbage_ratio: Option<f64>,
+    /// Amount of dead bytes that triggers a compaction whatever the ratio
+    pub dead_bytes: Option<u64>,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> CompactionPolicy {
+        CompactionPolicy {
+            garbage_ratio: Some(0.5),
+            dead_bytes: None,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub(super) fn triggers(&self, live_bytes: u64, dead_bytes: u64, max_file_size: u64) -> bool {
+        if let Some(limit) = self.dead_bytes {
+            if dead_bytes >= limit {
+                return true;
+            }
+        }
+        match self.garbage_ratio {
+            Some(ratio) => {
+                dead_bytes > max_file_size
+                    && dead_bytes as f64 >= ratio * (live_bytes + dead_bytes) as f64
+            }
+            None => false,
+        }
+    }
+}
+
+/// Builder of a KvStore - KvStore::open uses the default options
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KvStoreOptions {
+    pub(super) max_file_size: u64,
+    pub(super) sync: SyncPolicy,
+    pub(super) compaction: CompactionPolicy,
+    pub(super) read_only: bool,
+    pub(super) create_dirs: bool,
+    pub(super) max_readers_per_handle: usize,
+    pub(super) sweep_interval: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            sync: SyncPolicy::Never,
+            compaction: CompactionPolicy::default(),
+            read_only: false,
+            create_dirs: false,
+            max_readers_per_handle: DEFAULT_MAX_READERS_PER_HANDLE,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Default options
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Read the options from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<KvStoreOptions> {
+        let config = fs::read_to_string(path)?;
+        Ok(toml::from_str(&config)?)
+    }
+
+    /// Size past which the active file is sealed and a new one is started
+    pub fn max_file_size(mut self, max_file_size: u64) -> KvStoreOptions {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// When writes are forced to disk
+    pub fn sync(mut self, sync: SyncPolicy) -> KvStoreOptions {
+        self.sync = sync;
+        self
+    }
+
+    /// When compactions are started automatically
+    pub fn compaction(mut self, compaction: CompactionPolicy) -> KvStoreOptions {
+        self.compaction = compaction;
+        self
+    }
+
+    /// Open the store without ever writing to the directory.
+    /// Sets and removes fail with KvsError::ReadOnly
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Create the directory of the store if it does not exist
+    pub fn create_dirs(mut self, create_dirs: bool) -> KvStoreOptions {
+        self.create_dirs = create_dirs;
+        self
+    }
+
+    /// Maximum number of data files each handle keeps open for reading.
+    /// Every clone of a store is a handle with its own readers, so a store used from N
+    /// threads may keep N times that many files open
+    pub fn max_readers_per_handle(mut self, max_readers_per_handle: usize) -> KvStoreOptions {
+        self.max_readers_per_handle = max_readers_per_handle;
+        self
+    }
+
+    /// Milliseconds between two evictions of the expired keys from the index, 0 to never
+    /// evict them. Expired keys are absent either way, eviction only frees their memory
+    pub fn sweep_interval(mut self, sweep_interval: u64) -> KvStoreOptions {
+        self.sweep_interval = sweep_interval;
+        self
+    }
+
+    /// Open the store with these options
+    pub fn open<P: Into<PathBuf>>(self, directory: P) -> Result<KvStore> {
+        KvStore::open_with_options(directory, self)
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.max_file_size == 0 {
+            return Err(KvsError::InvalidOptions(
+                "max_file_size must be above 0".to_string(),
+            ));
+        }
+        if self.sync == SyncPolicy::EveryN(0) {
+            return Err(KvsError::InvalidOptions(
+                "every_n must be above 0".to_string(),
+            ));
+        }
+        if self.sync == SyncPolicy::Interval(0) {
+            return Err(KvsError::InvalidOptions(
+                "interval must be above 0".to_string(),
+            ));
+        }
+        if self.max_readers_per_handle == 0 {
+            return Err(KvsError::InvalidOptions(
+                "max_readers_per_handle must be above 0".to_string(),
+            ));
+        }
+        if let Some(ratio) = self.compaction.garbage_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(KvsError::InvalidOptions(
+                    "garbage_ratio must be between 0 and 1".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
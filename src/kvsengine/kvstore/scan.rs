@@ -0,0 +1,162 @@
+//! Ordered iteration over the keys of the store.
+//! The entries of the range are copied out of the index map when the scan starts, values are
+//! read from the data files as the iterator goes. A key removed meanwhile is skipped.
+use super::*;
+use std::ops::RangeBounds;
+
+/// Pairs of a range of keys in key order - Use rev() for the reverse order
+pub struct KvScan<'a> {
+    store: &'a KvStore,
+    entries: std::vec::IntoIter<KvIndex>,
+}
+
+impl KvStore {
+    /// Iterate over the pairs whose key is in the range
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<KvScan<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(KvScan {
+            store: self,
+            entries: self
+                .range_entries(bytes_range(range), None, false)?
+                .into_iter(),
+        })
+    }
+
+    /// Iterate over the pairs whose key starts with the prefix
+    pub fn range_prefix(&self, prefix: &str) -> Result<KvScan<'_>> {
+        self.range(prefix_range(prefix))
+    }
+
+    /// Copy at most limit entries of the range out of the index map
+    fn range_entries<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<KvIndex>> {
+        let now = now_timestamp();
+        let index_map = self.shared.index_map.read()?;
+        let limit = limit.unwrap_or(usize::MAX);
+        let entries = index_map
+            .range(range)
+            .map(|(_, index)| index)
+            .filter(|index| !index.is_expired(now))
+            .cloned();
+        Ok(if reverse {
+            entries.rev().take(limit).collect()
+        } else {
+            entries.take(limit).collect()
+        })
+    }
+
+    /// Read the value of an entry of a scan as strings.
+    /// None if the key was removed meanwhile or if the pair is not UTF-8
+    fn read_scanned(&self, index: KvIndex) -> Result<Option<(String, String)>> {
+        let value = match self.read_record(&index) {
+            // Compacted meanwhile - The slow path looks the key up again
+            Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                self.get_bytes(index.key.clone())?
+            }
+            record => record?.value,
+        };
+        Ok(value.and_then(|value| utf8_pair((index.key, value))))
+    }
+}
+
+impl<'a> Iterator for KvScan<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for index in &mut self.entries {
+            match self.store.read_scanned(index) {
+                Ok(Some(pair)) => return Some(Ok(pair)),
+                Ok(None) => continue,
+                Err(x) => return Some(Err(x)),
+            }
+        }
+        None
+    }
+}
+
+impl<'a> DoubleEndedIterator for KvScan<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(index) = self.entries.next_back() {
+            match self.store.read_scanned(index) {
+                Ok(Some(pair)) => return Some(Ok(pair)),
+                Ok(None) => continue,
+                Err(x) => return Some(Err(x)),
+            }
+        }
+        None
+    }
+}
+
+impl KvsScan for KvStore {
+    /// Matched against the index map, data files are not read
+    fn keys(&self, pattern: Option<&str>) -> Result<Vec<String>> {
+        let now = now_timestamp();
+        let index_map = self.shared.index_map.read()?;
+        let pattern = pattern.unwrap_or("*");
+        let mut keys = Vec::new();
+        for (key, index) in
+            index_map.range(bytes_range(prefix_range(&glob::literal_prefix(pattern))))
+        {
+            if index.is_expired(now) {
+                continue;
+            }
+            let key = match String::from_utf8(key.clone()) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if glob_match(pattern, &key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let now = now_timestamp();
+        let index_map = self.shared.index_map.read()?;
+        Ok(index_map
+            .values()
+            .filter(|index| !index.is_expired(now))
+            .count() as u64)
+    }
+
+    /// Entries are copied out of the index map a page at a time : Skipped pairs are made up
+    /// for with the next entries until the limit or the end of the range is reached
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut range = bytes_range(range);
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let wanted = limit - pairs.len();
+            let entries = self.range_entries(range.clone(), Some(wanted), reverse)?;
+            let last_key = match entries.last() {
+                Some(index) => index.key.clone(),
+                None => break,
+            };
+            let copied = entries.len();
+            for index in entries {
+                if let Some(pair) = self.read_scanned(index)? {
+                    pairs.push(pair);
+                }
+            }
+            if copied < wanted {
+                break;
+            }
+            if reverse {
+                range.1 = Bound::Excluded(last_key);
+            } else {
+                range.0 = Bound::Excluded(last_key);
+            }
+        }
+        Ok(pairs)
+    }
+}
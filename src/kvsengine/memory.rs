@@ -0,0 +1,228 @@
+//! Storage engine keeping everything in memory - Nothing survives the engine.
+//! Used by tests and to run the server as a throwaway cache. Expired keys are absent to every
+//! read and evicted by a sweeper thread, as in KvStore.
+use super::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use tracing::{debug, error};
+
+// Milliseconds between two evictions of the expired keys
+const SWEEP_INTERVAL: u64 = 1000;
+
+// What is stored under a key
+struct MemoryValue {
+    value: Vec<u8>,
+    // Milliseconds since the epoch after which the key is gone
+    expires_at: Option<u64>,
+}
+
+impl MemoryValue {
+    fn new(value: Vec<u8>, ttl: Option<Duration>) -> MemoryValue {
+        MemoryValue {
+            value,
+            expires_at: ttl.map(|ttl| now_timestamp().saturating_add(ttl.as_millis() as u64)),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// KvsEngine over an ordered map - Clones share the map
+#[derive(Clone)]
+pub struct MemoryEngine {
+    map: Arc<RwLock<BTreeMap<Vec<u8>, MemoryValue>>>,
+}
+
+impl Default for MemoryEngine {
+    fn default() -> MemoryEngine {
+        MemoryEngine::new()
+    }
+}
+
+impl MemoryEngine {
+    /// Empty engine - Its sweeper stops once every clone is dropped
+    pub fn new() -> MemoryEngine {
+        let engine = MemoryEngine {
+            map: Arc::new(RwLock::new(BTreeMap::new())),
+        };
+        let map = Arc::downgrade(&engine.map);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(SWEEP_INTERVAL));
+            let map = match map.upgrade() {
+                Some(map) => map,
+                None => return,
+            };
+            if let Err(x) = MemoryEngine::sweep_expired(&map) {
+                error!("Could not evict the expired keys : {:?}", x);
+            }
+        });
+        engine
+    }
+
+    /// Apply every operation of the batch at once
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut map = self.map.write()?;
+        for (key, value) in batch.into_operations() {
+            match value {
+                Some(value) => map.insert(key, MemoryValue::new(value, None)),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn sweep_expired(map: &RwLock<BTreeMap<Vec<u8>, MemoryValue>>) -> Result<()> {
+        let now = now_timestamp();
+        let mut map = map.write()?;
+        let count = map.len();
+        map.retain(|_, value| !value.is_expired(now));
+        if map.len() < count {
+            debug!("{} expired keys evicted", count - map.len());
+        }
+        Ok(())
+    }
+
+    fn insert(&self, key: Vec<u8>, value: MemoryValue) -> Result<()> {
+        self.map.write()?.insert(key, value);
+        Ok(())
+    }
+
+    /// Apply a closure to the value of a key - None if the key is absent or expired
+    fn lookup<T, F: FnOnce(&MemoryValue) -> T>(&self, key: &[u8], f: F) -> Result<Option<T>> {
+        let now = now_timestamp();
+        Ok(self
+            .map
+            .read()?
+            .get(key)
+            .filter(|value| !value.is_expired(now))
+            .map(f))
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, MemoryValue::new(value, None))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.lookup(&key, |value| value.value.clone())
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_timestamp();
+        match self.map.write()?.remove(&key) {
+            Some(value) if !value.is_expired(now) => Ok(()),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.insert(
+            key.into_bytes(),
+            MemoryValue::new(value.into_bytes(), Some(ttl)),
+        )
+    }
+
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        let now = now_timestamp();
+        match self.map.write()?.get_mut(key.as_bytes()) {
+            Some(value) if !value.is_expired(now) => {
+                value.expires_at = Some(now.saturating_add(ttl.as_millis() as u64));
+                Ok(())
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let now = now_timestamp();
+        self.lookup(key.as_bytes(), |value| {
+            value
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now)))
+        })?
+        .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn exists(&self, key: String) -> Result<bool> {
+        Ok(self.lookup(key.as_bytes(), |_| ())?.is_some())
+    }
+
+    /// Set under a single lock : Readers see every pair or none
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut map = self.map.write()?;
+        for (key, value) in pairs {
+            map.insert(key.into_bytes(), MemoryValue::new(value.into_bytes(), None));
+        }
+        Ok(())
+    }
+
+    /// Nothing is removed unless all the keys are in the engine
+    fn remove_many(&self, keys: Vec<String>) -> Result<()> {
+        let now = now_timestamp();
+        let mut map = self.map.write()?;
+        let present = |key: &String| {
+            map.get(key.as_bytes())
+                .is_some_and(|value| !value.is_expired(now))
+        };
+        if !keys.iter().all(present) {
+            return Err(KvsError::KeyNotFound);
+        }
+        for key in keys {
+            map.remove(key.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl KvsScan for MemoryEngine {
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        let now = now_timestamp();
+        let map = self.map.read()?;
+        let limit = limit.unwrap_or(usize::MAX);
+        let pairs = map
+            .range(bytes_range(range))
+            .filter(|(_, value)| !value.is_expired(now))
+            .filter_map(|(key, value)| utf8_pair((key.clone(), value.value.clone())));
+        Ok(if reverse {
+            pairs.rev().take(limit).collect()
+        } else {
+            pairs.take(limit).collect()
+        })
+    }
+
+    /// Matched against the keys only, values are not copied
+    fn keys(&self, pattern: Option<&str>) -> Result<Vec<String>> {
+        let now = now_timestamp();
+        let map = self.map.read()?;
+        let pattern = pattern.unwrap_or("*");
+        let mut keys = Vec::new();
+        for (key, value) in map.range(bytes_range(prefix_range(&glob::literal_prefix(pattern)))) {
+            if value.is_expired(now) {
+                continue;
+            }
+            let key = match String::from_utf8(key.clone()) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if glob_match(pattern, &key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let now = now_timestamp();
+        let map = self.map.read()?;
+        Ok(map.values().filter(|value| !value.is_expired(now)).count() as u64)
+    }
+}
@@ -0,0 +1,196 @@
+//! Storage engine backed by sled - Only compiled with the sled feature.
+//! Values are stored along with their expiry, encoded with bincode. Expired keys are absent
+//! to every read and dropped the next time they are read : sled has no compaction of ours
+//! to leave them behind, and there is no sweeper.
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tracing::debug;
+
+// What is stored under a key
+#[derive(Serialize, Deserialize)]
+struct SledValue {
+    value: Vec<u8>,
+    // Milliseconds since the epoch after which the key is gone
+    expires_at: Option<u64>,
+}
+
+impl SledValue {
+    fn new(value: Vec<u8>, ttl: Option<Duration>) -> SledValue {
+        SledValue {
+            value,
+            expires_at: ttl.map(|ttl| now_timestamp().saturating_add(ttl.as_millis() as u64)),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// KvsEngine on top of a sled database - Clones share the database
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: ::sled::Db,
+}
+
+impl SledKvsEngine {
+    /// Open or create the sled database of a directory
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine {
+            db: ::sled::open(directory)?,
+        })
+    }
+
+    fn insert(&self, key: Vec<u8>, value: SledValue) -> Result<()> {
+        self.db.insert(key, bincode::serialize(&value)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Value stored under a key - None if the key is absent or expired
+    fn lookup(&self, key: &[u8]) -> Result<Option<SledValue>> {
+        let bytes = match self.db.get(key)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let value: SledValue = bincode::deserialize(&bytes)?;
+        if value.is_expired(now_timestamp()) {
+            debug!("{} expired - Removing it", String::from_utf8_lossy(key));
+            // Unless it was written again meanwhile
+            let _ = self
+                .db
+                .compare_and_swap(key, Some(bytes), None as Option<&[u8]>)?;
+            return Ok(None);
+        }
+        Ok(Some(value))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, SledValue::new(value, None))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup(&key)?.map(|value| value.value))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        if self.lookup(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.db.remove(key)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.insert(
+            key.into_bytes(),
+            SledValue::new(value.into_bytes(), Some(ttl)),
+        )
+    }
+
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        let value = self.lookup(key.as_bytes())?.ok_or(KvsError::KeyNotFound)?;
+        self.insert(key.into_bytes(), SledValue::new(value.value, Some(ttl)))
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let value = self.lookup(key.as_bytes())?.ok_or(KvsError::KeyNotFound)?;
+        let now = now_timestamp();
+        Ok(value
+            .expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+    }
+
+    /// Applied as one sled batch
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = ::sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(
+                key.as_bytes(),
+                bincode::serialize(&SledValue::new(value.into_bytes(), None))?,
+            );
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Applied as one sled transaction, aborted if a key is missing.
+    /// Repeated keys are removed once, the transaction would find them missing otherwise
+    fn remove_many(&self, keys: Vec<String>) -> Result<()> {
+        let keys: BTreeSet<String> = keys.into_iter().collect();
+        let now = now_timestamp();
+        let removed = self.db.transaction(|db| {
+            for key in &keys {
+                let live = match db.remove(key.as_bytes())? {
+                    Some(bytes) => bincode::deserialize::<SledValue>(&bytes)
+                        .map(|value| !value.is_expired(now))
+                        .unwrap_or(false),
+                    None => false,
+                };
+                if !live {
+                    return ::sled::transaction::abort(());
+                }
+            }
+            Ok(())
+        });
+        match removed {
+            Ok(()) => {
+                self.db.flush()?;
+                Ok(())
+            }
+            Err(::sled::transaction::TransactionError::Abort(())) => Err(KvsError::KeyNotFound),
+            Err(::sled::transaction::TransactionError::Storage(x)) => Err(KvsError::Sled(x)),
+        }
+    }
+}
+
+impl KvsScan for SledKvsEngine {
+    fn scan_range(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        let now = now_timestamp();
+        let entries = self.db.range(bytes_range(range));
+        let entries: Box<dyn Iterator<Item = ::sled::Result<(::sled::IVec, ::sled::IVec)>>> =
+            if reverse {
+                Box::new(entries.rev())
+            } else {
+                Box::new(entries)
+            };
+        let mut pairs = Vec::new();
+        for entry in entries {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (key, bytes) = entry?;
+            let value: SledValue = bincode::deserialize(&bytes)?;
+            if value.is_expired(now) {
+                continue;
+            }
+            if let Some(pair) = utf8_pair((key.to_vec(), value.value)) {
+                pairs.push(pair);
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let now = now_timestamp();
+        let mut count = 0;
+        for entry in self.db.iter() {
+            let (_, bytes) = entry?;
+            let value: SledValue = bincode::deserialize(&bytes)?;
+            if !value.is_expired(now) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
@@ -1,53 +1,68 @@
 use crate::errors::*;
-use crate::kvmessage::KvMessage;
-use crate::kvsengine::kvstore::KvStore;
+use crate::kvmessage::{ErrorCode, KvMessage, KvResponse, PROTOCOL_VERSION};
+use crate::kvsengine::KvsScan;
+use crate::threadpool::{SharedQueueThreadPool, ThreadPool};
+use message_io::network::{Endpoint, NetEvent, Transport};
+use message_io::node::{self, NodeHandler};
 
-use crate::kvsengine::KvsEngine;
-use message_io::network::{NetEvent, Transport};
-use message_io::node::{self};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info};
 
-use std::net::Ipv4Addr;
-use std::net::{SocketAddr, SocketAddrV4};
-use tracing::{debug, info};
+// Largest page sent back for a scan, whatever the limit asked by the client
+const MAX_SCAN_PAGE: u32 = 1000;
 
-/// Server structure - Only hold the networks infos and functions
-pub struct Kvserver {
+/// Server structure - Hold the networks infos and the engine requests run against.
+/// Any engine keeping its keys in order can be served
+pub struct Kvserver<E: KvsScan> {
     local_socketadr: SocketAddr,
+    engine: E,
 }
 
-impl Kvserver {
+impl<E: KvsScan> Kvserver<E> {
     /// Initializer of the server struc
-    pub fn new(local_addr: &str, local_port: u16) -> Kvserver {
-        let ipvadr = local_addr.parse::<Ipv4Addr>();
-        match ipvadr {
-            Ok(addr) => {
-                return Kvserver {
-                    local_socketadr: SocketAddr::V4(SocketAddrV4::new(addr, local_port)),
-                };
-            }
-            Err(x) => {
-                debug!("Following error occurred : {:?}", x);
-                panic!("Connexion could not initiate on the specified error");
-            }
+    /// The engine is opened by the caller, the server only hands clones of it to the pool
+    pub fn new(local_addr: SocketAddr, engine: E) -> Kvserver<E> {
+        Kvserver {
+            local_socketadr: local_addr,
+            engine,
         }
     }
 
     /// Main function to run the loop for server
     /// handle connexions, requests and returns
+    /// Requests run on a shared queue thread pool with one thread per CPU
     pub fn run_server(&mut self) -> Result<()> {
-        //First, intiate the store - This can take some time if indexes need to be rebuilt
-        let mut my_store: KvStore = KvStore::open(std::env::current_dir()?)?;
+        let threads = thread::available_parallelism()
+            .map(|threads| threads.get() as u32)
+            .unwrap_or(4);
+        self.run_with_pool(SharedQueueThreadPool::new(threads)?)
+    }
+
+    /// Same as run_server with requests running on the given pool.
+    /// The network loop only decodes requests and checks handshakes : The pool runs the
+    /// requests against the store and sends the responses back through the message-io handler
+    pub fn run_with_pool<P: ThreadPool>(&mut self, pool: P) -> Result<()> {
+        let my_store = self.engine.clone();
+        // Endpoints that went through the handshake
+        let mut greeted: HashSet<Endpoint> = HashSet::new();
+        // Handles of the engine between two jobs - Each one keeps its readers open
+        let idle_stores: Arc<Mutex<Vec<E>>> = Arc::new(Mutex::new(Vec::new()));
 
         //Finaly, we can connect and start to wait for events
         let (handler, listener) = node::split::<()>();
         handler
             .network()
-            .listen(Transport::Tcp, self.local_socketadr)
-            .unwrap();
-        println!("Listening to connexions...");
+            .listen(Transport::FramedTcp, self.local_socketadr)?;
+        info!("Listening to connexions on {}...", self.local_socketadr);
         listener.for_each(move |event| match event.network() {
             NetEvent::Connected(_, _) => (),
             NetEvent::Disconnected(endpoint) => {
+                greeted.remove(&endpoint);
                 info!("{} just disconnected", endpoint.addr());
             }
             NetEvent::Accepted(_endpoint, _listener) => {
@@ -60,75 +75,173 @@ impl Kvserver {
                 // in FramedTcp mode.
                 // Cost of this should not weight in for now regarding other optimisation than can
                 // be done
-                if input_data.len() > 0 {
-                    let message: KvMessage = bincode::deserialize(&input_data).unwrap();
-                    let mut _reponse = vec![];
-                    match message {
-                        KvMessage::Response(_) => {
-                            println!("Response received");
-                        }
-                        KvMessage::Get(key) => {
-                            match my_store.get(key) {
-                                Ok(Some(value)) => {
-                                    _reponse =
-                                        bincode::serialize(&KvMessage::Response(value)).unwrap();
-                                }
-                                Ok(None) => {
-                                    _reponse = bincode::serialize(&KvMessage::Response(
-                                        "Key not found".to_string(),
-                                    ))
-                                    .unwrap()
-                                }
-                                Err(z) => {
-                                    _reponse =
-                                        bincode::serialize(&KvMessage::Response(format!("{:?}", z)))
-                                            .unwrap()
-                                }
-                            }
-                            handler.network().send(endpoint, &_reponse);
+                if input_data.is_empty() {
+                    return;
+                }
+                let response = match bincode::deserialize::<KvMessage>(input_data) {
+                    Ok(KvMessage::Handshake(version)) => {
+                        if version == PROTOCOL_VERSION {
+                            greeted.insert(endpoint);
+                            KvResponse::Handshake(PROTOCOL_VERSION)
+                        } else {
+                            debug!("Client speaks protocol version {}", version);
+                            KvResponse::error(
+                                ErrorCode::VersionMismatch,
+                                format!("Server speaks protocol version {}", PROTOCOL_VERSION),
+                            )
                         }
-                        KvMessage::Set(key, value) => {
-                            debug!("Set command was issued - Trying to process");
-                            match my_store.set(key, value) {
-                                Ok(_) => {
-                                    debug!("Set command done successfuly");
-                                    _reponse =
-                                        bincode::serialize(&KvMessage::Response("ok".to_string()))
-                                            .unwrap()
-                                }
-                                Err(err) => {
-                                    debug!("Error occured during the set command");
-                                    _reponse = bincode::serialize(&KvMessage::Response(format!(
-                                        "{:?}",
-                                        err
-                                    )))
-                                    .unwrap()
-                                }
+                    }
+                    Ok(message) => {
+                        if greeted.contains(&endpoint) {
+                            let store = match idle_stores.lock() {
+                                Ok(mut idle_stores) => idle_stores.pop(),
+                                Err(_) => None,
                             }
-                            handler.network().send(endpoint, &_reponse);
-                        }
-                        KvMessage::Remove(key) => {
-                            match my_store.remove(key) {
-                                Ok(_) => {
-                                    _reponse =
-                                        bincode::serialize(&KvMessage::Response("ok".to_string()))
-                                            .unwrap();
+                            .unwrap_or_else(|| my_store.clone());
+                            let idle_stores = Arc::clone(&idle_stores);
+                            let handler = handler.clone();
+                            pool.spawn(move || {
+                                let response = process_request(&store, message);
+                                send_response(&handler, endpoint, &response);
+                                if let Ok(mut idle_stores) = idle_stores.lock() {
+                                    idle_stores.push(store);
                                 }
-                                Err(err) => {
-                                    _reponse = bincode::serialize(&KvMessage::Response(format!(
-                                        "{:?}",
-                                        err
-                                    )))
-                                    .unwrap()
-                                }
-                            }
-                            handler.network().send(endpoint, &_reponse);
+                            });
+                            return;
                         }
+                        KvResponse::error(
+                            ErrorCode::HandshakeRequired,
+                            "Handshake must be sent first".to_string(),
+                        )
                     }
-                }
+                    Err(err) => {
+                        debug!("Could not decode the request : {:?}", err);
+                        KvResponse::error(ErrorCode::InvalidRequest, format!("{}", err))
+                    }
+                };
+                send_response(&handler, endpoint, &response);
             }
         });
 
         Ok(())
     }
 }
+
+fn send_response(handler: &NodeHandler<()>, endpoint: Endpoint, response: &KvResponse) {
+    match bincode::serialize(response) {
+        Ok(output_data) => {
+            handler.network().send(endpoint, &output_data);
+        }
+        Err(x) => error!("Could not encode the response : {:?}", x),
+    }
+}
+
+/// Run a request against the store and build the response sent back to the client
+pub(crate) fn process_request<E: KvsScan>(store: &E, message: KvMessage) -> KvResponse {
+    match message {
+        KvMessage::Handshake(_) => KvResponse::Handshake(PROTOCOL_VERSION),
+        KvMessage::Get(key) => match store.get(key) {
+            Ok(value) => KvResponse::Value(value),
+            Err(err) => err.into(),
+        },
+        KvMessage::Set(key, value) => {
+            debug!("Set command was issued - Trying to process");
+            match store.set(key, value) {
+                Ok(_) => {
+                    debug!("Set command done successfuly");
+                    KvResponse::Ok
+                }
+                Err(err) => {
+                    debug!("Error occured during the set command");
+                    err.into()
+                }
+            }
+        }
+        KvMessage::Remove(key) => match store.remove(key) {
+            Ok(_) => KvResponse::Ok,
+            Err(err) => err.into(),
+        },
+        KvMessage::MSet(pairs) => {
+            debug!("MSet command was issued for {} keys", pairs.len());
+            match store.set_many(pairs) {
+                Ok(_) => KvResponse::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        KvMessage::MGet(keys) => match store.get_many(keys) {
+            Ok(values) => KvResponse::Values(values),
+            Err(err) => err.into(),
+        },
+        KvMessage::MDel(keys) => match store.remove_many(keys) {
+            Ok(_) => KvResponse::Ok,
+            Err(err) => err.into(),
+        },
+        KvMessage::Scan { start, end, limit } => scan_page(store, start, end, limit),
+        KvMessage::Keys(pattern) => match store.keys(pattern.as_deref()) {
+            Ok(keys) => KvResponse::Keys(keys),
+            Err(err) => err.into(),
+        },
+        KvMessage::Count => match store.count() {
+            Ok(count) => KvResponse::Count(count),
+            Err(err) => err.into(),
+        },
+        KvMessage::Exists(key) => match store.exists(key) {
+            Ok(exists) => KvResponse::Exists(exists),
+            Err(err) => err.into(),
+        },
+        KvMessage::SetWithTtl(key, value, ttl) => {
+            match store.set_with_ttl(key, value, Duration::from_millis(ttl)) {
+                Ok(_) => KvResponse::Ok,
+                Err(err) => err.into(),
+            }
+        }
+        KvMessage::Expire(key, ttl) => match store.expire(key, Duration::from_millis(ttl)) {
+            Ok(_) => KvResponse::Ok,
+            Err(err) => err.into(),
+        },
+        KvMessage::Ttl(key) => match store.ttl(key) {
+            Ok(ttl) => KvResponse::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)),
+            Err(err) => err.into(),
+        },
+        KvMessage::SetBytes(key, value) => match store.set_bytes(key, value) {
+            Ok(_) => KvResponse::Ok,
+            Err(err) => err.into(),
+        },
+        KvMessage::GetBytes(key) => match store.get_bytes(key) {
+            Ok(value) => KvResponse::Bytes(value),
+            Err(err) => err.into(),
+        },
+        KvMessage::RemoveBytes(key) => match store.remove_bytes(key) {
+            Ok(_) => KvResponse::Ok,
+            Err(err) => err.into(),
+        },
+    }
+}
+
+/// Read one page of a scan - One more pair is read to know where the next page starts
+fn scan_page<E: KvsScan>(
+    store: &E,
+    start: Option<String>,
+    end: Option<String>,
+    limit: u32,
+) -> KvResponse {
+    let limit = match limit {
+        0 => MAX_SCAN_PAGE,
+        limit => limit.min(MAX_SCAN_PAGE),
+    } as usize;
+    let range = (
+        start.map_or(Bound::Unbounded, Bound::Included),
+        end.map_or(Bound::Unbounded, Bound::Excluded),
+    );
+    match store.scan_range(range, Some(limit + 1), false) {
+        Ok(mut pairs) => {
+            let next = if pairs.len() > limit {
+                pairs.pop().map(|(key, _)| key)
+            } else {
+                None
+            };
+            KvResponse::Page { pairs, next }
+        }
+        Err(err) => err.into(),
+    }
+}
@@ -8,10 +8,17 @@
 pub mod errors;
 /// Network message module
 pub mod kvmessage;
+/// Async server and client module
+#[cfg(feature = "async")]
+pub mod kvsasync;
+/// Client structure module
+pub mod kvsclient;
 /// Engine module
 pub mod kvsengine;
 /// Server structure module
 pub mod kvsserver;
+/// Thread pools running the requests of the server
+pub mod threadpool;
 
 /// To redistributes errors;
 pub use errors::{KvsError, Result};
@@ -0,0 +1,32 @@
+use crate::errors::*;
+
+mod naive;
+mod rayon;
+mod sharedqueue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::sharedqueue::SharedQueueThreadPool;
+
+/// ThreadPool trait used by the server to run requests away from the network loop
+pub trait ThreadPool {
+    /// Create a pool running jobs on the given number of threads - At least one
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Run a job on the pool - A job that panics does not take the pool down
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+// A pool without threads would take jobs and never run them
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::ThreadPool(
+            "A thread pool needs at least one thread".to_owned(),
+        ));
+    }
+    Ok(())
+}
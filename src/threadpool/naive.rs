@@ -0,0 +1,20 @@
+use super::{check_threads, ThreadPool};
+use crate::Result;
+use std::thread;
+
+/// Not a pool at all : Every job gets its own thread
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(threads: u32) -> Result<NaiveThreadPool> {
+        check_threads(threads)?;
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,29 @@
+use super::{check_threads, ThreadPool};
+use crate::errors::*;
+use tracing::error;
+
+/// Thread pool backed by rayon
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<RayonThreadPool> {
+        // rayon would pick a default size for 0
+        check_threads(threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler rayon aborts the process when a job panics
+            .panic_handler(|_| error!("A job panicked in the rayon thread pool"))
+            .build()
+            .map_err(|x| KvsError::ThreadPool(x.to_string()))?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
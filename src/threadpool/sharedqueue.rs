@@ -0,0 +1,83 @@
+use super::{check_threads, ThreadPool};
+use crate::errors::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{debug, error};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads taking their jobs from one shared queue.
+/// A thread whose job panicked is replaced, so the pool keeps its size
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            spawn_worker(Worker {
+                receiver: Arc::clone(&receiver),
+            })?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Workers only stop once the pool is dropped, the queue is always open here
+        if self.sender.send(Box::new(job)).is_err() {
+            error!("No worker left to run the job");
+        }
+    }
+}
+
+// Owns the receiving side of the queue for one thread
+struct Worker {
+    receiver: Arc<Mutex<Receiver<Job>>>,
+}
+
+impl Drop for Worker {
+    // The worker is dropped while unwinding when its job panicked : A new thread takes over
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("A job panicked - Replacing its worker thread");
+            let worker = Worker {
+                receiver: Arc::clone(&self.receiver),
+            };
+            if let Err(x) = spawn_worker(worker) {
+                error!("Could not replace the worker thread : {:?}", x);
+            }
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || run_jobs(worker))?;
+    Ok(())
+}
+
+fn run_jobs(worker: Worker) {
+    loop {
+        // The lock is released before the job runs so that a panic never poisons it
+        let job = match worker.receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => {
+                error!("Job queue lock poisoned - Stopping the worker");
+                return;
+            }
+        };
+        match job {
+            Ok(job) => job(),
+            Err(_) => {
+                debug!("Thread pool dropped - Stopping the worker");
+                return;
+            }
+        }
+    }
+}
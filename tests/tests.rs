@@ -1,12 +1,25 @@
+// The first tests borrow their argument arrays and import more than they use
+#![allow(clippy::needless_borrows_for_generic_args, unused_imports)]
 use assert_cmd::prelude::*;
+use kvs::kvmessage::{KvMessage, KvResponse};
+use kvs::kvsclient::Kvclient;
 use kvs::kvsengine::kvstore::KvStore;
-use kvs::kvsengine::KvsEngine;
+use kvs::kvsengine::{
+    check_engine, glob_match, prefix_range, CompactionPolicy, KvStoreOptions, KvsEngine, KvsScan,
+    MemoryEngine, SyncPolicy, WriteBatch,
+};
 use kvs::kvsserver::Kvserver;
 use kvs::kvsserver::*;
-use kvs::Result;
+use kvs::threadpool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsError, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::net::SocketAddr;
+use std::ops::Bound;
 use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -69,7 +82,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -93,12 +106,50 @@ fn cli_get_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs keys [PATTERN]`, `kvs count` and `kvs exists <KEY>` should answer from the store.
+#[test]
+fn cli_keys_count_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("user:2".to_owned(), "value2".to_owned())?;
+    store.set("group:1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "user:*"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1\nuser:2\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["count"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("3").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exists", "group:2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("false").trim());
+
+    Ok(())
+}
+
 // `kvs rm <KEY>` should print nothing and exit with zero.
 #[test]
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -181,11 +232,449 @@ fn cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client` with no args should exit with a non-zero code.
+#[test]
+fn client_cli_no_args() {
+    Command::cargo_bin("kvs-client").unwrap().assert().failure();
+}
+
+#[test]
+fn client_cli_invalid_get() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "extra", "field"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "invalid-addr"])
+        .assert()
+        .failure();
+}
+
+// `kvs-client` should exit with a non-zero code when no server is listening.
+#[test]
+fn client_cli_no_server() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &free_addr().to_string()])
+        .assert()
+        .failure()
+        .stdout(is_empty());
+}
+
+// `kvs-server` should refuse an unknown engine.
+#[test]
+fn server_cli_invalid_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` should refuse a configuration file it does not understand.
+#[test]
+fn server_cli_invalid_config() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("kvs.toml"), "max_file_size = \"big\"").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--addr", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` should refuse a directory created by another engine.
+#[test]
+fn server_cli_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server --engine sled` should refuse a directory created by kvs, marked or not.
+#[cfg(feature = "sled")]
+#[test]
+fn server_cli_sled_on_kvs_directory() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // Filled by the kvs command, which writes no engine file
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("conf").exists());
+}
+
+// Directories without engine file should be told by their files.
+#[test]
+fn engine_detection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        check_engine(temp_dir.path(), "sled"),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    check_engine(temp_dir.path(), "kvs")?;
+    assert!(!temp_dir.path().join("engine").exists());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("conf"), "")?;
+    assert!(matches!(
+        check_engine(temp_dir.path(), "kvs"),
+        Err(KvsError::WrongEngine { .. })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_engine(temp_dir.path(), "kvs")?;
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("engine"))?,
+        "kvs"
+    );
+    assert!(matches!(
+        check_engine(temp_dir.path(), "sled"),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    Ok(())
+}
+
+// An address nobody listens on, with a port chosen by the system.
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("unable to find a free port")
+}
+
+// Wait until a server accepts connexions.
+fn wait_for_server(addr: SocketAddr) {
+    for _ in 0..250 {
+        if std::net::TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("Server at {} did not start", addr);
+}
+
+// Serve a store in a temporary directory - The directory lives as long as the returned value.
+fn spawn_server() -> (SocketAddr, TempDir) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let data_dir = temp_dir.path().to_path_buf();
+    thread::spawn(move || Kvserver::new(addr, KvStore::open(data_dir)?).run_server());
+    wait_for_server(addr);
+    (addr, temp_dir)
+}
+
+// A server started on a temporary directory should answer a client going through the handshake.
+#[test]
+fn client_server_requests() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    assert_eq!(
+        client.send(&KvMessage::Set("key1".to_owned(), "value1".to_owned()))?,
+        KvResponse::Ok
+    );
+    assert_eq!(
+        client.send(&KvMessage::Get("key1".to_owned()))?,
+        KvResponse::Value(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        client.send(&KvMessage::Get("key2".to_owned()))?,
+        KvResponse::Value(None)
+    );
+    assert_eq!(
+        client.send(&KvMessage::Remove("key2".to_owned()))?,
+        KvResponse::NotFound
+    );
+    assert_eq!(
+        client.send(&KvMessage::Remove("key1".to_owned()))?,
+        KvResponse::Ok
+    );
+    Ok(())
+}
+
+// Batch messages should set, get and remove several keys at once.
+#[test]
+fn client_server_batch() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    client.send(&KvMessage::Set("key1".to_owned(), "value1".to_owned()))?;
+    assert_eq!(
+        client.send(&KvMessage::MSet(vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]))?,
+        KvResponse::Ok
+    );
+    assert_eq!(
+        client.send(&KvMessage::MGet(vec![
+            "key1".to_owned(),
+            "key4".to_owned(),
+            "key3".to_owned()
+        ]))?,
+        KvResponse::Values(vec![
+            Some("value1".to_owned()),
+            None,
+            Some("value3".to_owned())
+        ])
+    );
+    assert_eq!(
+        client.send(&KvMessage::MDel(vec!["key1".to_owned(), "key4".to_owned()]))?,
+        KvResponse::NotFound
+    );
+    assert_eq!(
+        client.send(&KvMessage::MDel(vec!["key1".to_owned(), "key2".to_owned()]))?,
+        KvResponse::Ok
+    );
+    assert_eq!(
+        client.send(&KvMessage::MGet(vec!["key2".to_owned(), "key3".to_owned()]))?,
+        KvResponse::Values(vec![None, Some("value3".to_owned())])
+    );
+    Ok(())
+}
+
+// Scans should come in pages chained by their next key.
+#[test]
+fn client_server_scan() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    let pairs: Vec<(String, String)> = (0..5)
+        .map(|id| (format!("page{}", id), format!("value{}", id)))
+        .collect();
+    client.send(&KvMessage::MSet(pairs.clone()))?;
+    client.send(&KvMessage::Set("other".to_owned(), "value".to_owned()))?;
+    let mut scanned = Vec::new();
+    let mut start = Some("page".to_owned());
+    while let Some(page_start) = start {
+        match client.send(&KvMessage::Scan {
+            start: Some(page_start),
+            end: Some("pagf".to_owned()),
+            limit: 2,
+        })? {
+            KvResponse::Page { pairs, next } => {
+                assert!(pairs.len() <= 2);
+                scanned.extend(pairs);
+                start = next;
+            }
+            other => panic!("Unexpected response: {:?}", other),
+        }
+    }
+    assert_eq!(scanned, pairs);
+    Ok(())
+}
+
+// Keys should be listed, counted and checked without reading their values.
+#[test]
+fn client_server_keys() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    let pairs: Vec<(String, String)> = (0..5)
+        .map(|id| (format!("page{}", id), format!("value{}", id)))
+        .collect();
+    client.send(&KvMessage::MSet(pairs))?;
+    assert_eq!(
+        client.send(&KvMessage::Keys(Some("page[13]".to_owned())))?,
+        KvResponse::Keys(vec!["page1".to_owned(), "page3".to_owned()])
+    );
+    assert_eq!(client.send(&KvMessage::Count)?, KvResponse::Count(5));
+    assert_eq!(
+        client.send(&KvMessage::Exists("page3".to_owned()))?,
+        KvResponse::Exists(true)
+    );
+    assert_eq!(
+        client.send(&KvMessage::Exists("key1".to_owned()))?,
+        KvResponse::Exists(false)
+    );
+    Ok(())
+}
+
+// Keys set with a time to live should report it, missing keys cannot be given one.
+#[test]
+fn client_server_ttl() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    assert_eq!(
+        client.send(&KvMessage::SetWithTtl(
+            "session".to_owned(),
+            "token".to_owned(),
+            60_000
+        ))?,
+        KvResponse::Ok
+    );
+    match client.send(&KvMessage::Ttl("session".to_owned()))? {
+        KvResponse::Ttl(Some(ttl)) => assert!(ttl > 50_000 && ttl <= 60_000),
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    client.send(&KvMessage::Set("key3".to_owned(), "value3".to_owned()))?;
+    assert_eq!(
+        client.send(&KvMessage::Ttl("key3".to_owned()))?,
+        KvResponse::Ttl(None)
+    );
+    assert_eq!(
+        client.send(&KvMessage::Expire("key1".to_owned(), 1000))?,
+        KvResponse::NotFound
+    );
+    Ok(())
+}
+
+// Byte messages should store anything, the String messages refuse values that are not UTF-8.
+#[test]
+fn client_server_bytes() -> Result<()> {
+    let (addr, _temp_dir) = spawn_server();
+    let client = Kvclient::new(addr);
+    assert_eq!(
+        client.send(&KvMessage::SetBytes(vec![0xff, 0], vec![0, 1, 0xfe]))?,
+        KvResponse::Ok
+    );
+    assert_eq!(
+        client.send(&KvMessage::GetBytes(vec![0xff, 0]))?,
+        KvResponse::Bytes(Some(vec![0, 1, 0xfe]))
+    );
+    assert_eq!(
+        client.send(&KvMessage::SetBytes(b"key2".to_vec(), vec![0xff]))?,
+        KvResponse::Ok
+    );
+    assert!(matches!(
+        client.send(&KvMessage::Get("key2".to_owned()))?,
+        KvResponse::Error { .. }
+    ));
+    assert_eq!(
+        client.send(&KvMessage::RemoveBytes(vec![0xff, 0]))?,
+        KvResponse::Ok
+    );
+    assert_eq!(
+        client.send(&KvMessage::GetBytes(vec![0xff, 0]))?,
+        KvResponse::Bytes(None)
+    );
+    Ok(())
+}
+
+// Many clients at once should be served by the pool of the server.
+#[test]
+fn client_server_concurrent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let data_dir = temp_dir.path().to_path_buf();
+    thread::spawn(move || {
+        Kvserver::new(addr, KvStore::open(data_dir)?).run_with_pool(RayonThreadPool::new(4)?)
+    });
+    wait_for_server(addr);
+
+    let clients: Vec<_> = (0..8)
+        .map(|client_id| {
+            thread::spawn(move || -> Result<()> {
+                let client = Kvclient::new(addr);
+                for key_id in 0..10 {
+                    let key = format!("key{}_{}", client_id, key_id);
+                    let value = format!("value{}", key_id);
+                    assert_eq!(
+                        client.send(&KvMessage::Set(key.clone(), value.clone()))?,
+                        KvResponse::Ok
+                    );
+                    assert_eq!(
+                        client.send(&KvMessage::Get(key))?,
+                        KvResponse::Value(Some(value))
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().expect("client thread panicked")?;
+    }
+    Ok(())
+}
+
+// Async clients should share the async server, each one on its own connexion
+#[cfg(feature = "async")]
+#[test]
+fn async_client_server() -> Result<()> {
+    use kvs::kvsasync::{KvsAsyncServer, KvsClient};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let runtime = tokio::runtime::Runtime::new()?;
+    let server = KvsAsyncServer::new(addr, KvStore::open(temp_dir.path())?);
+    runtime.spawn(async move { server.run_server().await });
+    wait_for_server(addr);
+
+    runtime.block_on(async {
+        let clients: Vec<_> = (0..8)
+            .map(|client_id| {
+                tokio::spawn(async move {
+                    let mut client = KvsClient::connect(addr).await?;
+                    for key_id in 0..10 {
+                        let key = format!("key{}_{}", client_id, key_id);
+                        let value = format!("value{}", key_id);
+                        assert_eq!(
+                            client
+                                .send(&KvMessage::Set(key.clone(), value.clone()))
+                                .await?,
+                            KvResponse::Ok
+                        );
+                        assert_eq!(
+                            client.send(&KvMessage::Get(key.clone())).await?,
+                            KvResponse::Value(Some(value))
+                        );
+                        assert_eq!(
+                            client.send(&KvMessage::Remove(key.clone())).await?,
+                            KvResponse::Ok
+                        );
+                        assert_eq!(
+                            client.send(&KvMessage::Get(key)).await?,
+                            KvResponse::Value(None)
+                        );
+                    }
+                    Ok::<(), KvsError>(())
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.expect("client task panicked")?;
+        }
+        Ok(())
+    })
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -195,7 +684,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -206,7 +695,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -215,7 +704,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -227,14 +716,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -243,7 +732,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -251,7 +740,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
 
@@ -261,13 +750,1160 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// A removed key should stay removed once the store is opened again.
+#[test]
+fn remove_key_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Batches should be written at once, and removed only if every key exists.
+#[test]
+fn batch_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+
+    let pairs: Vec<(String, String)> = (0..20)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    store.set_many(pairs)?;
+    store.set_many(vec![
+        ("key0".to_owned(), "first".to_owned()),
+        ("key0".to_owned(), "last".to_owned()),
+    ])?;
+    let keys = vec!["key0".to_owned(), "key19".to_owned(), "key20".to_owned()];
+    assert_eq!(
+        store.get_many(keys.clone())?,
+        vec![Some("last".to_owned()), Some("value19".to_owned()), None]
+    );
+
+    assert!(matches!(
+        store.remove_many(keys.clone()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key0".to_owned())?, Some("last".to_owned()));
+    store.remove_many(vec![
+        "key0".to_owned(),
+        "key19".to_owned(),
+        "key0".to_owned(),
+    ])?;
+
+    drop(store);
+    let store = small_files().open(temp_dir.path())?;
+    assert_eq!(store.get_many(keys)?, vec![None, None, None]);
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// A batch cut short by a crash should be discarded as a whole on open.
+#[test]
+fn write_batch_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // The index file covers the committed batch, it is not rebuilt
+    let index_file = temp_dir.path().join("kvindex.idx");
+    let index_data = std::fs::read(&index_file)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::read(&index_file)?, index_data);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    batch.remove("key2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Tear the commit marker of the last batch
+    let data_file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("file_0.bdd"))?;
+    let data_len = data_file.metadata()?.len();
+    data_file.set_len(data_len - 1)?;
+    drop(data_file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Scans should list pairs in key order, forward and backward.
+#[test]
+fn range_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key in &["b1", "a", "b", "b2", "c", "b\u{10ffff}"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    assert_eq!(
+        keys(store.scan(..)?),
+        vec!["a", "b", "b1", "b2", "b\u{10ffff}"]
+    );
+    assert_eq!(
+        keys(store.scan("a".to_owned().."b2".to_owned())?),
+        vec!["a", "b", "b1"]
+    );
+    assert_eq!(
+        keys(store.scan_rev("b".to_owned()..)?),
+        vec!["b\u{10ffff}", "b2", "b1", "b"]
+    );
+    assert_eq!(
+        keys(store.scan_prefix("b")?),
+        vec!["b", "b1", "b2", "b\u{10ffff}"]
+    );
+    assert_eq!(keys(store.scan_prefix("c")?), Vec::<String>::new());
+    assert_eq!(
+        prefix_range("b\u{10ffff}"),
+        (
+            Bound::Included("b\u{10ffff}".to_owned()),
+            Bound::Excluded("c".to_owned())
+        )
+    );
+
+    let last_two: Vec<(String, String)> = store
+        .range_prefix("b")?
+        .rev()
+        .take(2)
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        last_two,
+        vec![
+            ("b\u{10ffff}".to_owned(), "value_b\u{10ffff}".to_owned()),
+            ("b2".to_owned(), "value_b2".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Keys should be listed and counted from the index, with glob patterns.
+#[test]
+fn keys_count_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user:1", "user:12", "user:2", "users", "group:1"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("user:2".to_owned())?;
+
+    assert_eq!(store.keys(Some("user:*"))?, vec!["user:1", "user:12"]);
+    assert_eq!(store.keys(Some("user?"))?, vec!["users"]);
+    assert_eq!(store.keys(Some("*:1"))?, vec!["group:1", "user:1"]);
+    assert_eq!(store.keys(None)?.len(), 4);
+    assert_eq!(store.count()?, 4);
+    assert!(store.exists("users".to_owned())?);
+    assert!(!store.exists("user:2".to_owned())?);
+
+    assert!(glob_match("a[b-d]e", "ace"));
+    assert!(!glob_match("a[!b-d]e", "ace"));
+    assert!(glob_match("a\\*", "a*"));
+    assert!(!glob_match("a\\*", "ab"));
+    assert!(glob_match("*b*c", "abxbc"));
+    assert!(glob_match("[a", "[a"));
+    assert!(!glob_match("a*", "b"));
+    Ok(())
+}
+
+// Expired keys should be absent, evicted by the sweeper and dropped by compaction.
+#[test]
+fn key_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    assert!(store.ttl("key1".to_owned())? <= Some(Duration::from_millis(200)));
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("key4".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.expire("key2".to_owned(), Duration::from_millis(200))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!store.exists("key1".to_owned())?);
+    assert_eq!(store.keys(None)?, vec!["key3"]);
+    assert_eq!(store.count()?, 1);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    // The expiry is persisted, expired keys stay absent
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.ttl("key3".to_owned())? > Some(Duration::from_secs(50)));
+
+    // Compaction leaves expired records behind
+    store.set_with_ttl(
+        "key5".to_owned(),
+        "short_lived".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    store.compaction()?;
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.expect("unable to walk the directory").into_path();
+        if path.extension().is_some_and(|extension| extension == "bdd") {
+            assert!(!String::from_utf8_lossy(&std::fs::read(path)?).contains("short_lived"));
+        }
+    }
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// The sweeper should evict expired keys from the index.
+#[test]
+fn expiry_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .sweep_interval(50)
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("key10".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(400));
+
+    // Once evicted the keys are gone for the compaction too
+    store.compaction()?;
+    assert_eq!(store.keys(None)?, vec!["key10"]);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 1);
+    Ok(())
+}
+
+// An empty key should be a key like any other, whatever the engine.
+#[test]
+fn empty_key() -> Result<()> {
+    fn check_empty_key(engine: &impl KvsScan) -> Result<()> {
+        engine.set(String::new(), "value".to_owned())?;
+        assert_eq!(engine.get(String::new())?, Some("value".to_owned()));
+        assert!(engine.exists(String::new())?);
+        assert_eq!(engine.count()?, 1);
+        engine.remove(String::new())?;
+        assert_eq!(engine.get(String::new())?, None);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_empty_key(&KvStore::open(temp_dir.path())?)?;
+    check_empty_key(&MemoryEngine::new())?;
+    #[cfg(feature = "sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_empty_key(&kvs::kvsengine::SledKvsEngine::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+// The sled engine should behave like KvStore.
+#[cfg(feature = "sled")]
+#[test]
+fn sled_engine() -> Result<()> {
+    use kvs::kvsengine::SledKvsEngine;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_many(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        engine.remove_many(vec!["key1".to_owned(), "key4".to_owned()]),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    engine.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(engine.ttl("key4".to_owned())?.is_some());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    assert_eq!(engine.keys(Some("key*"))?, vec!["key2", "key3"]);
+    assert_eq!(
+        engine.scan_rev(..)?,
+        vec![
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.count()?, 2);
+    engine.set_bytes(vec![0xff], vec![0, 0xfe])?;
+    assert_eq!(engine.get_bytes(vec![0xff])?, Some(vec![0, 0xfe]));
+    assert_eq!(engine.count()?, 3);
+    assert_eq!(engine.scan(..)?.len(), 2);
+    // A repeated key is removed once
+    engine.remove_many(vec!["key2".to_owned(), "key2".to_owned()])?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Keys and values that are not UTF-8 should be stored as they are.
+// The String API refuses them, String listings skip them.
+#[test]
+fn byte_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value: Vec<u8> = (0..=255).collect();
+    let store = small_files().open(temp_dir.path())?;
+    store.set_bytes(vec![0xff, 0xfe], value.clone())?;
+    store.set_bytes(b"text".to_vec(), vec![0xc3])?;
+    let mut batch = WriteBatch::new();
+    batch.set(vec![0x80], vec![0u8; 10]);
+    batch.set("key1", "value1");
+    store.write_batch(batch)?;
+    for key_id in 0..100 {
+        store.set(format!("other{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get_bytes(vec![0xff, 0xfe])?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert!(matches!(
+        store.get("text".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    store.compaction()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xff, 0xfe])?, Some(value.clone()));
+    assert_eq!(store.get_bytes(vec![0x80])?, Some(vec![0u8; 10]));
+    store.remove_bytes(vec![0x80])?;
+    assert!(matches!(
+        store.remove_bytes(vec![0x80]),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.scan_prefix("key")?.len(), 1);
+    store.set("u1".to_owned(), "value1".to_owned())?;
+    store.set("u2".to_owned(), "value2".to_owned())?;
+    let keys = store.keys(None)?;
+    assert_eq!(keys.len(), 104);
+    assert!(keys.contains(&"text".to_owned()));
+    assert_eq!(store.count()?, 105);
+    assert_eq!(store.scan(..)?.len(), 103);
+    // The pair of text is skipped and made up for with the next ones
+    let pairs = store.scan_range(
+        (Bound::Included("other99".to_owned()), Bound::Unbounded),
+        Some(3),
+        false,
+    )?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["other99", "u1", "u2"]);
+
+    let engine = MemoryEngine::new();
+    engine.set_bytes(vec![0xff], value.clone())?;
+    assert_eq!(engine.get_bytes(vec![0xff])?, Some(value));
+    engine.set_bytes(b"raw".to_vec(), vec![0xff])?;
+    assert!(matches!(
+        engine.get("raw".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        engine.keys(None)?,
+        vec!["key1".to_owned(), "raw".to_owned()]
+    );
+    assert_eq!(engine.scan(..)?.len(), 1);
+    assert_eq!(engine.count()?, 3);
+    Ok(())
+}
+
+// The memory engine should behave as the disk engine, batches and expiry included.
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_many(vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        engine.remove_many(vec!["key1".to_owned(), "key4".to_owned()]),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key5".to_owned(), "value5".to_owned());
+    batch.remove("key1".to_owned());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    engine.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(engine.ttl("key4".to_owned())?.is_some());
+    assert_eq!(engine.ttl("key2".to_owned())?, None);
+    thread::sleep(Duration::from_millis(200));
+    assert!(!engine.exists("key4".to_owned())?);
+    assert!(matches!(
+        engine.remove("key4".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // Clones share the same map
+    let clone = engine.clone();
+    clone.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.keys(Some("key*"))?, vec!["key2", "key3", "key5"]);
+    assert_eq!(
+        engine.scan_rev("key".to_owned().."key5".to_owned())?,
+        vec![
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(engine.scan_prefix("oth")?.len(), 1);
+    assert_eq!(engine.count()?, 4);
+    engine.remove_many(vec!["key2".to_owned(), "key2".to_owned()])?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A server on the memory engine should answer without touching its data directory.
+#[test]
+fn server_cli_memory_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    wait_for_server(addr);
+
+    let client = Kvclient::new(addr);
+    let set = client.send(&KvMessage::Set("key1".to_owned(), "value1".to_owned()));
+    let get = client.send(&KvMessage::Get("key1".to_owned()));
+    server.kill()?;
+    assert_eq!(set?, KvResponse::Ok);
+    assert_eq!(get?, KvResponse::Value(Some("value1".to_owned())));
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+    Ok(())
+}
+
+// Data should be found again when the index file is lost or truncated.
+#[test]
+fn rebuild_missing_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+    drop(store);
+
+    std::fs::remove_file(temp_dir.path().join("kvindex.idx"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    drop(store);
+
+    let index_file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("kvindex.idx"))?;
+    let index_len = index_file.metadata()?.len();
+    index_file.set_len(index_len / 2)?;
+    drop(index_file);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key7".to_owned())?, None);
+    for key_id in (0..50).filter(|key_id| *key_id != 7) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    // A damaged entry length must not be trusted
+    let mut index = b"KVSI\x01\x00\x00\x00".to_vec();
+    index.extend_from_slice(&[0xff; 8]);
+    std::fs::write(temp_dir.path().join("kvindex.idx"), index)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    Ok(())
+}
+
+// Small data files so that a few records are enough to seal files.
+fn small_files() -> KvStoreOptions {
+    KvStoreOptions::new().max_file_size(280)
+}
+
+// Options read from a TOML file should be applied and unknown settings refused.
+#[test]
+fn options_from_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config_file = temp_dir.path().join("kvs.toml");
+    std::fs::write(
+        &config_file,
+        "max_file_size = 280\ncreate_dirs = true\n[compaction]\ngarbage_ratio = 0.8\n",
+    )?;
+    let store_dir = temp_dir.path().join("store");
+    let store = KvStoreOptions::from_file(&config_file)?.open(&store_dir)?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    assert!(store_dir.join("file_1.bdd").exists());
+
+    std::fs::write(&config_file, "max_size = 280\n")?;
+    assert!(matches!(
+        KvStoreOptions::from_file(&config_file),
+        Err(KvsError::Toml(_))
+    ));
+    assert!(matches!(
+        KvStoreOptions::new()
+            .max_readers_per_handle(0)
+            .open(temp_dir.path()),
+        Err(KvsError::InvalidOptions(_))
+    ));
+    Ok(())
+}
+
+// Writes should reach the files as the sync policy says, or when flushed explicitly.
+#[test]
+fn sync_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_len = || {
+        std::fs::metadata(temp_dir.path().join("file_0.bdd"))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    };
+
+    let store = KvStoreOptions::new()
+        .sync(SyncPolicy::Never)
+        .open(temp_dir.path())?;
+    // Only the header of the data file is there
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(data_len(), 8);
+    store.flush()?;
+    let flushed_len = data_len();
+    assert!(flushed_len > 8);
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .sync(SyncPolicy::EveryN(2))
+        .open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(data_len(), flushed_len);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(data_len() > flushed_len);
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .sync(SyncPolicy::EveryWrite)
+        .open(temp_dir.path())?;
+    let synced_len = data_len();
+    store.remove("key1".to_owned())?;
+    assert!(data_len() > synced_len);
+    drop(store);
+
+    // An idle store still gets its writes synced once the interval is over
+    let store = KvStoreOptions::new()
+        .sync(SyncPolicy::Interval(100))
+        .open(temp_dir.path())?;
+    let synced_len = data_len();
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(data_len(), synced_len);
+    thread::sleep(Duration::from_millis(500));
+    assert!(data_len() > synced_len);
+    drop(store);
+
+    let config_file = temp_dir.path().join("kvs.toml");
+    std::fs::write(&config_file, "sync = { interval = 1000 }\n")?;
+    let store = KvStoreOptions::from_file(&config_file)?.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.sync()?;
+    Ok(())
+}
+
+// A missing directory should only be created when asked to.
+#[test]
+fn create_dirs_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("a").join("b");
+    assert!(KvStore::open(&store_dir).is_err());
+    assert!(!store_dir.exists());
+    KvStoreOptions::new().create_dirs(true).open(&store_dir)?;
+    assert!(store_dir.exists());
+    Ok(())
+}
+
+// A read-only store should serve reads, refuse writes and leave the directory untouched.
+#[test]
+fn read_only_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("kvindex.idx"))?;
+    std::fs::remove_file(temp_dir.path().join("file_0.hint"))?;
+
+    let store = small_files()
+        .read_only(true)
+        .max_readers_per_handle(1)
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert!(matches!(
+        store.set("key0".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compaction(), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert!(!temp_dir.path().join("kvindex.idx").exists());
+    assert!(!temp_dir.path().join("file_0.hint").exists());
+    Ok(())
+}
+
+// Sealed data files should get a hint file, and a lost hint file should be written again.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key3".to_owned())?;
+    // Seal the file holding the tombstone as well
+    for key_id in 100..120 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let store = small_files().open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    let hint_file = temp_dir.path().join("file_0.hint");
+    assert!(hint_file.exists());
+    std::fs::remove_file(&hint_file)?;
+    // A damaged entry length makes the hint file unusable
+    let mut hint = b"KVSH\x01\x00\x00\x00".to_vec();
+    hint.extend_from_slice(&[0xff; 8]);
+    std::fs::write(temp_dir.path().join("file_1.hint"), hint)?;
+
+    let store = small_files().open(temp_dir.path())?;
+    assert!(hint_file.exists());
+    assert_eq!(store.get("key3".to_owned())?, None);
+    for key_id in (0..120).filter(|key_id| *key_id != 3) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A damaged record should be reported with its location instead of being returned.
+#[test]
+fn corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    // Flip the last byte of the first record of the first data file
+    let data_file = temp_dir.path().join("file_0.bdd");
+    let mut data = std::fs::read(&data_file)?;
+    let key_length = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+    let value_length = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+    data[8 + 32 + key_length + value_length - 1] ^= 0xff;
+    std::fs::write(&data_file, data)?;
+
+    let store = small_files().open(temp_dir.path())?;
+    match store.get("key0".to_owned()) {
+        Err(KvsError::Corrupted { file, offset }) => {
+            assert_eq!(file, data_file);
+            assert_eq!(offset, 8);
+        }
+        other => panic!("Corruption was not detected: {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // Without its hint file, the data file is scanned and the damage is found on open
+    std::fs::remove_file(temp_dir.path().join("file_0.hint"))?;
+    assert!(matches!(
+        small_files().open(temp_dir.path()),
+        Err(KvsError::Corrupted { .. })
+    ));
+    Ok(())
+}
+
+// A damaged length should be reported before it sizes a read.
+#[test]
+fn corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    // Flip the high byte of the key length of the first record of the first data file
+    let data_file = temp_dir.path().join("file_0.bdd");
+    let mut data = std::fs::read(&data_file)?;
+    data[8 + 7] ^= 0x7f;
+    std::fs::write(&data_file, data)?;
+
+    let store = small_files().open(temp_dir.path())?;
+    match store.get("key0".to_owned()) {
+        Err(KvsError::Corrupted { file, offset }) => {
+            assert_eq!(file, data_file);
+            assert_eq!(offset, 8);
+        }
+        other => panic!("Corruption was not detected: {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Append a record the way the first versions did, its size negated once removed
+fn write_first_record(data: &mut Vec<u8>, key: &str, value: &str, removed: bool) {
+    let record = serde_json::json!({"key": key, "value": value}).to_string();
+    let size_of_record = record.len() as i64;
+    let size_of_record = if removed {
+        -size_of_record
+    } else {
+        size_of_record
+    };
+    data.extend_from_slice(&size_of_record.to_ne_bytes());
+    data.extend_from_slice(record.as_bytes());
+}
+
+// Append a record the way versions storing checksummed JSON records did
+fn write_legacy_record(data: &mut Vec<u8>, record: serde_json::Value) {
+    let record = record.to_string();
+    data.extend_from_slice(&record.len().to_ne_bytes());
+    data.extend_from_slice(&crc32fast::hash(record.as_bytes()).to_ne_bytes());
+    data.extend_from_slice(record.as_bytes());
+}
+
+// Append a binary record the way versions without data file header did
+fn write_unversioned_record(data: &mut Vec<u8>, key: &str, value: &str) {
+    let mut header = Vec::new();
+    header.extend_from_slice(&0x4b56u16.to_ne_bytes());
+    header.extend_from_slice(&[1, 1]);
+    header.extend_from_slice(&(key.len() as u32).to_ne_bytes());
+    header.extend_from_slice(&(value.len() as u32).to_ne_bytes());
+    header.extend_from_slice(&42u64.to_ne_bytes());
+    header.extend_from_slice(&0u64.to_ne_bytes());
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+    header.extend_from_slice(&hasher.finalize().to_ne_bytes());
+    data.extend_from_slice(&header);
+    data.extend_from_slice(key.as_bytes());
+    data.extend_from_slice(value.as_bytes());
+}
+
+// Directories of older versions should be refused on open and converted by the migration.
+#[test]
+fn migrate_old_formats() -> Result<()> {
+    use serde_json::json;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sealed = Vec::new();
+    write_legacy_record(&mut sealed, json!({"key": "key1", "value": "value1"}));
+    write_legacy_record(&mut sealed, json!({"key": "key2", "value": "value2"}));
+    let mut unversioned = Vec::new();
+    write_unversioned_record(&mut unversioned, "key4", "value4");
+    let mut active = Vec::new();
+    write_legacy_record(
+        &mut active,
+        json!({"key": "key1", "value": null, "timestamp": 1}),
+    );
+    write_legacy_record(
+        &mut active,
+        json!({"key": "", "value": null, "marker": "Begin"}),
+    );
+    write_legacy_record(&mut active, json!({"key": "key3", "value": "value3"}));
+    write_legacy_record(
+        &mut active,
+        json!({"key": "", "value": null, "marker": "Commit"}),
+    );
+    // Torn write at the end of the active file
+    active.extend_from_slice(&[1, 2, 3]);
+    std::fs::write(temp_dir.path().join("file_0.bdd"), sealed)?;
+    std::fs::write(temp_dir.path().join("file_1.bdd"), unversioned)?;
+    std::fs::write(temp_dir.path().join("file_2.bdd"), active)?;
+    std::fs::write(temp_dir.path().join("file_0.hint"), b"stale")?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::LegacyFormat(_))
+    ));
+    assert_eq!(KvStore::migrate(temp_dir.path())?, 7);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    // Nothing is left to convert
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("0 records migrated"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.count()?, 3);
+    Ok(())
+}
+
+// A directory of the first versions should be converted by kvs migrate, removed keys included.
+#[test]
+fn migrate_first_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // set key1 value1, set key2 value2, rm key1, set key3 value3 then a torn write
+    let mut data = Vec::new();
+    write_first_record(&mut data, "key1", "value1", true);
+    write_first_record(&mut data, "key2", "value2", false);
+    write_first_record(&mut data, "key3", "value3", false);
+    data.extend_from_slice(&[1, 2, 3]);
+    std::fs::write(temp_dir.path().join("file_0.bdd"), data)?;
+    std::fs::write(temp_dir.path().join("kvindex.idx"), b"old index")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("3 records migrated"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.count()?, 2);
+    Ok(())
+}
+
+// Data and index files should start with their magic and a little-endian format version.
+#[test]
+fn file_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let data_file = temp_dir.path().join("file_0.bdd");
+    let index_file = temp_dir.path().join("kvindex.idx");
+    assert_eq!(&std::fs::read(&data_file)?[..8], b"KVSD\x01\x00\x00\x00");
+    assert_eq!(&std::fs::read(&index_file)?[..8], b"KVSI\x01\x00\x00\x00");
+
+    // An index file of another format is rebuilt
+    std::fs::write(&index_file, b"KVSI\x09\x00\x00\x00")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(&std::fs::read(&index_file)?[..8], b"KVSI\x01\x00\x00\x00");
+
+    // A data file written by a newer version is refused
+    let mut data = std::fs::read(&data_file)?;
+    data[4] = 2;
+    std::fs::write(&data_file, data)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedVersion { file, version }) => {
+            assert_eq!(file, data_file);
+            assert_eq!(version, 2);
+        }
+        other => panic!("Unknown version was not refused: {:?}", other.is_ok()),
+    }
+    Ok(())
+}
+
+// Files left behind by an interrupted compaction should be cleaned up on open.
+#[test]
+fn compaction_leftovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.compaction()?;
+    drop(store);
+
+    // A data file older than the manifest and an unfinished compaction file
+    let obsolete_file = temp_dir.path().join("file_0.bdd");
+    let unfinished_file = temp_dir.path().join("file_9999.new");
+    assert!(!obsolete_file.exists());
+    std::fs::write(&obsolete_file, "garbage")?;
+    std::fs::write(&unfinished_file, "garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!obsolete_file.exists());
+    assert!(!unfinished_file.exists());
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value2".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Overwriting the same keys should start compactions without any explicit call.
+#[test]
+fn automatic_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    drop(store);
+
+    assert!(temp_dir.path().join("kvmanifest").exists());
+    assert!(!temp_dir.path().join("file_0.bdd").exists());
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A background compaction should be installed once merged, without waiting for another write.
+#[test]
+fn idle_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().sweep_interval(0).open(temp_dir.path())?;
+    store.set_compaction_policy(CompactionPolicy {
+        garbage_ratio: None,
+        dead_bytes: None,
+    })?;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let data_file = temp_dir.path().join("file_0.bdd");
+    assert!(data_file.exists());
+
+    // The last write starts a compaction, nothing polls it afterwards
+    store.set_compaction_policy(CompactionPolicy {
+        garbage_ratio: None,
+        dead_bytes: Some(0),
+    })?;
+    store.set("key0".to_owned(), "value19".to_owned())?;
+    for _ in 0..250 {
+        if !data_file.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!data_file.exists());
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A background compaction that fails should leave the old files in charge and never fail writes.
+#[test]
+fn failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    // Damage the live record of key0 so that merging it fails
+    let data_file = temp_dir.path().join("file_0.bdd");
+    let mut data = std::fs::read(&data_file)?;
+    let key_length = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+    let value_length = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+    data[8 + 32 + key_length + value_length - 1] ^= 0xff;
+    std::fs::write(&data_file, data)?;
+
+    let store = small_files().open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 1..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    thread::sleep(Duration::from_millis(100));
+    store.set("key1".to_owned(), "value19".to_owned())?;
+    assert!(matches!(
+        store.compaction(),
+        Err(KvsError::Corrupted { .. })
+    ));
+    assert!(data_file.exists());
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        assert_ne!(entry?.path().extension(), Some("new".as_ref()));
+    }
+    drop(store);
+
+    let store = small_files().open(temp_dir.path())?;
+    for key_id in 1..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Clones of a store should serve reads and writes from many threads at once,
+// background compactions included.
+#[test]
+fn concurrent_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = small_files().open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..20 {
+                for key_id in 0..10 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", iter))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", iter)));
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            for key_id in 0..10 {
+                assert_eq!(
+                    store.get(format!("key{}_{}", thread_id, key_id))?,
+                    Some("value19".to_owned())
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&small_files().open(temp_dir.path())?)
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -287,7 +1923,7 @@ fn compaction() -> Result<()> {
             let value = format!("{}", iter);
             store.set(key, value)?;
         }
-        store.compaction();
+        store.compaction()?;
 
         let new_size = dir_size();
         if new_size > current_size {
@@ -298,7 +1934,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -309,3 +1945,58 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Run jobs on a pool and wait for all of them.
+fn run_jobs<P: ThreadPool>(pool: &P, jobs: usize) {
+    let (sender, receiver) = mpsc::channel();
+    for job in 0..jobs {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(job).unwrap());
+    }
+    let mut done: Vec<usize> = receiver.iter().take(jobs).collect();
+    done.sort_unstable();
+    assert_eq!(done, (0..jobs).collect::<Vec<usize>>());
+}
+
+// Every pool should run every job it is given.
+#[test]
+fn thread_pools() -> Result<()> {
+    run_jobs(&NaiveThreadPool::new(4)?, 50);
+    run_jobs(&SharedQueueThreadPool::new(4)?, 50);
+    run_jobs(&RayonThreadPool::new(4)?, 50);
+    Ok(())
+}
+
+// A pool without threads should be refused rather than never run its jobs.
+#[test]
+fn thread_pools_without_threads() {
+    assert!(matches!(
+        NaiveThreadPool::new(0),
+        Err(KvsError::ThreadPool(_))
+    ));
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::ThreadPool(_))
+    ));
+    assert!(matches!(
+        RayonThreadPool::new(0),
+        Err(KvsError::ThreadPool(_))
+    ));
+}
+
+// Jobs that panic should not take the threads of the pool with them.
+#[test]
+fn thread_pools_panic_recovery() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..8 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    run_jobs(&pool, 50);
+
+    let pool = RayonThreadPool::new(4)?;
+    for _ in 0..8 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    run_jobs(&pool, 50);
+    Ok(())
+}